@@ -0,0 +1,281 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Checks of the backed up files against size and file count limits.
+//!
+//! Every line of the rules file is a path, `<=` and comma separated limits,
+//! e.g. `home/*/Downloads <= 20G, 10000 files`. The path is a wildcard
+//! like in filter patterns and the limits apply to every file or directory
+//! it matches separately, `/` is the whole backup set. Empty lines and lines
+//! starting with `#` are skipped.
+use crate::entry::{Entry, Sink};
+use crate::filters::match_wildcard;
+use crate::report::{file_size, human_size};
+use anyhow::{Context, Result, bail};
+use clio::Output;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::io::{BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Exit status when any limit is exceeded, distinct from the one of errors.
+pub const EXCEEDED_EXIT_CODE: u8 = 3;
+
+/// Error returned once all exceeded limits were written, `main` exits with
+/// [`EXCEEDED_EXIT_CODE`] on it.
+#[derive(Debug)]
+pub struct BudgetExceeded(pub usize);
+
+impl fmt::Display for BudgetExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} budget limits exceeded", self.0)
+    }
+}
+
+impl std::error::Error for BudgetExceeded {}
+
+struct Rule {
+    /// Line as written in the rules file.
+    text: String,
+    /// Wildcard matching paths, empty for the whole backup set.
+    path: String,
+    max_bytes: Option<u64>,
+    max_files: Option<u64>,
+}
+
+/// Parses size like `20G`, `1.5TiB` or `300`, units are binary.
+fn parse_size(text: &str) -> Option<u64> {
+    let number_end = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, unit) = text.split_at(number_end);
+    let number: f64 = number.parse().ok()?;
+    let unit = unit.trim();
+    let unit = unit
+        .strip_suffix("iB")
+        .or(unit.strip_suffix('B'))
+        .unwrap_or(unit);
+    let exponent = match unit.to_ascii_uppercase().as_str() {
+        "" => 0,
+        "K" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        _ => return None,
+    };
+    Some((number * 1024f64.powi(exponent)) as u64)
+}
+
+fn files(count: u64) -> String {
+    match count {
+        1 => "1 file".to_owned(),
+        _ => format!("{count} files"),
+    }
+}
+
+fn parse_rule(line: &str) -> Result<Rule> {
+    let Some((path, limits)) = line.split_once("<=") else {
+        bail!("expected `PATH <= LIMIT`");
+    };
+    let path = path.trim().trim_end_matches('/');
+    let mut rule = Rule {
+        text: line.to_owned(),
+        path: path.trim_start_matches('/').to_owned(),
+        max_bytes: None,
+        max_files: None,
+    };
+    if path.is_empty() && !line.trim_start().starts_with('/') {
+        bail!("missing path, use `/` for the whole backup set");
+    }
+    for limit in limits.split(',').map(str::trim) {
+        if let Some(files) = limit.strip_suffix("files").or(limit.strip_suffix("file")) {
+            let files = files.trim().parse().context("invalid number of files")?;
+            rule.max_files = Some(files);
+        } else {
+            let Some(bytes) = parse_size(limit) else {
+                bail!("invalid limit {limit:?}, expected size like `20G` or `1000 files`");
+            };
+            rule.max_bytes = Some(bytes);
+        }
+    }
+    Ok(rule)
+}
+
+#[derive(Default)]
+struct Usage {
+    files: u64,
+    bytes: u64,
+}
+
+pub struct Budget {
+    output: Output,
+    roots: Vec<(PathBuf, PathBuf)>,
+    rules: Vec<Rule>,
+    /// Usage of every path matched by a rule, keyed by the rule index.
+    usage: BTreeMap<(usize, PathBuf), Usage>,
+    unreadable: u64,
+}
+
+impl Budget {
+    pub fn new(output: Output, roots: Vec<(PathBuf, PathBuf)>, rules_path: &Path) -> Result<Self> {
+        let contents = std::fs::read_to_string(rules_path)
+            .with_context(|| format!("failed to read budget file {}", rules_path.display()))?;
+        let mut rules = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let rule = parse_rule(line)
+                .with_context(|| format!("{}:{}: {line}", rules_path.display(), i + 1))?;
+            rules.push(rule);
+        }
+        Ok(Budget {
+            output,
+            roots,
+            rules,
+            usage: BTreeMap::new(),
+            unreadable: 0,
+        })
+    }
+}
+
+impl Sink for Budget {
+    fn entry(&mut self, entry: &Entry) -> Result<()> {
+        if !entry.included || entry.is_dir {
+            return Ok(());
+        }
+        let Ok(size) = file_size(&self.roots, entry) else {
+            self.unreadable += 1;
+            return Ok(());
+        };
+        // The file counts towards itself and all directories it is in.
+        for path in entry.path.ancestors() {
+            for (i, rule) in self.rules.iter().enumerate() {
+                let matched = match rule.path.as_str() {
+                    "" => path.as_os_str().is_empty(),
+                    p => match_wildcard(path.as_os_str().as_bytes(), p.as_bytes()),
+                };
+                if matched {
+                    let usage = self.usage.entry((i, path.to_owned())).or_default();
+                    usage.files += 1;
+                    usage.bytes += size;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let mut out = BufWriter::new(self.output);
+        let mut violations = 0;
+        for ((i, path), usage) in &self.usage {
+            let rule = &self.rules[*i];
+            let path = match path.as_os_str().is_empty() {
+                true => Path::new("/"),
+                false => path,
+            };
+            if let Some(max) = rule.max_bytes
+                && usage.bytes > max
+            {
+                violations += 1;
+                writeln!(
+                    out,
+                    "{}: {} exceeds {} ({})",
+                    path.display(),
+                    human_size(usage.bytes),
+                    human_size(max),
+                    rule.text
+                )?;
+            }
+            if let Some(max) = rule.max_files
+                && usage.files > max
+            {
+                violations += 1;
+                writeln!(
+                    out,
+                    "{}: {} {} {} ({})",
+                    path.display(),
+                    files(usage.files),
+                    if usage.files == 1 {
+                        "exceeds"
+                    } else {
+                        "exceed"
+                    },
+                    files(max),
+                    rule.text
+                )?;
+            }
+        }
+        if self.unreadable > 0 {
+            writeln!(
+                out,
+                "{} files couldn't be read and are not counted",
+                self.unreadable
+            )?;
+        }
+        out.into_inner()?.finish()?;
+        if violations > 0 {
+            return Err(BudgetExceeded(violations).into());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sizes() {
+        assert_eq!(parse_size("300"), Some(300));
+        assert_eq!(parse_size("2K"), Some(2048));
+        assert_eq!(parse_size("20G"), Some(20 << 30));
+        assert_eq!(parse_size("20 GB"), Some(20 << 30));
+        assert_eq!(parse_size("1.5TiB"), Some(3 << 39));
+        assert_eq!(parse_size("1p"), Some(1 << 50));
+        for invalid in ["", "G", "20X", "20 files", "-1G", "1..5G"] {
+            assert_eq!(parse_size(invalid), None, "{invalid}");
+        }
+    }
+
+    #[test]
+    fn rules() {
+        let rule = parse_rule("/ <= 500G, 2000000 files").unwrap();
+        assert_eq!(rule.path, "");
+        assert_eq!(rule.max_bytes, Some(500 << 30));
+        assert_eq!(rule.max_files, Some(2000000));
+
+        let rule = parse_rule("/home/*/Downloads/ <= 1 file").unwrap();
+        assert_eq!(rule.path, "home/*/Downloads");
+        assert_eq!(rule.max_bytes, None);
+        assert_eq!(rule.max_files, Some(1));
+
+        let rule = parse_rule("*.iso<=1G").unwrap();
+        assert_eq!(rule.path, "*.iso");
+        assert_eq!(rule.max_bytes, Some(1 << 30));
+        assert_eq!(rule.text, "*.iso<=1G");
+    }
+
+    #[test]
+    fn invalid_rules() {
+        for (line, error) in [
+            ("home 20G", "expected `PATH <= LIMIT`"),
+            (" <= 20G", "missing path"),
+            ("home <= 20X", "invalid limit"),
+            ("home <= many files", "invalid number of files"),
+            ("home <= 20G,", "invalid limit"),
+        ] {
+            let err = parse_rule(line).err().unwrap();
+            assert!(format!("{err:#}").contains(error), "{line}: {err:#}");
+        }
+    }
+
+    #[test]
+    fn file_counts() {
+        assert_eq!(files(0), "0 files");
+        assert_eq!(files(1), "1 file");
+        assert_eq!(files(2), "2 files");
+    }
+}
@@ -0,0 +1,173 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Comparison of two sets of backed up files.
+use crate::entry::{Entry, Sink};
+use crate::log::{LineParser, LogArgs};
+use crate::ncdu::{Metadata, TreeOptions, TreeWriter};
+use crate::repository::Repository;
+use crate::{Format, list, log, ncdu};
+use anyhow::Result;
+use clap::ValueEnum;
+use clio::{Input, Output};
+use std::collections::BTreeMap;
+use std::io::BufReader;
+use std::os::linux::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum DiffSource {
+    /// Logs from `duplicacy -debug -log backup -enum-only`
+    Log,
+    /// Outputs of `duplicacy list -files -r N`
+    List,
+    /// NCDU JSON Exports, e.g. created by duplicacy-du before
+    Ncdu,
+}
+
+#[derive(clap::Args, Debug)]
+pub struct DiffArgs {
+    /// Older input
+    old: Input,
+
+    /// Newer input
+    new: Input,
+
+    /// Format of both inputs
+    #[arg(short, long, value_enum, default_value_t = DiffSource::Log)]
+    source: DiffSource,
+
+    /// Output to write NCDU Export with growth of every file
+    #[arg(short, long, default_value = "-")]
+    output: Output,
+
+    /// Format of the NCDU Export
+    #[arg(short, long, value_enum, default_value_t = Format::NcduJson)]
+    format: Format,
+
+    #[command(flatten)]
+    log: LogArgs,
+
+    /// Repository directory to stat files of logs in, by default found by
+    /// looking for `.duplicacy` in the current directory and its parents
+    #[arg(long)]
+    repository: Option<PathBuf>,
+
+    /// Number of paths to list in every section of the summary
+    #[arg(short = 'n', long, default_value_t = 10)]
+    top: usize,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Size {
+    asize: u64,
+    dsize: u64,
+}
+
+/// Reads all files included in the input with their sizes. Sizes missing in
+/// the input are taken from the filesystem at `root`, files that are no
+/// longer there have zero size, so they still show up as removed.
+fn read_files(
+    source: DiffSource,
+    input: Input,
+    root: &Path,
+    parser: &LineParser,
+) -> Result<BTreeMap<PathBuf, Size>> {
+    let mut files = BTreeMap::new();
+    let mut missing = 0;
+    let mut add = |entry: &Entry| {
+        if entry.is_dir || !entry.included {
+            return Ok(());
+        }
+        let size = match entry.size {
+            Some(asize) => Size {
+                asize,
+                dsize: entry.stored_size.unwrap_or(asize),
+            },
+            None => {
+                let Ok(meta) = std::fs::symlink_metadata(root.join(entry.path)) else {
+                    missing += 1;
+                    files.insert(entry.path.to_owned(), Size::default());
+                    return Ok(());
+                };
+                Size {
+                    asize: meta.st_size(),
+                    dsize: meta.st_blocks() * 512,
+                }
+            }
+        };
+        files.insert(entry.path.to_owned(), size);
+        Ok(())
+    };
+    match source {
+        DiffSource::Log => log::read_log(BufReader::new(input), parser, &mut add)?,
+        DiffSource::List => list::read_list(BufReader::new(input), &mut add)?,
+        DiffSource::Ncdu => ncdu::json::read_export(BufReader::new(input), &mut add)?,
+    }
+    if missing > 0 {
+        eprintln!("warning: {missing} files from the input no longer exist, their size is unknown");
+    }
+    Ok(files)
+}
+
+fn print_top(title: &str, mut rows: Vec<(&Path, i128)>, top: usize) {
+    let total: i128 = rows.iter().map(|(_, s)| s).sum();
+    eprintln!("{title}: {} files, {total:+} bytes", rows.len());
+    rows.sort_by_key(|&(path, size)| (-size.abs(), path));
+    for (path, size) in rows.iter().take(top) {
+        eprintln!("  {size:>+16}  {}", path.display());
+    }
+}
+
+pub fn run(args: DiffArgs) -> Result<()> {
+    let repository = Repository::find(args.repository.as_deref())?;
+    if let DiffSource::Log = args.source {
+        repository.warn_if_not_found();
+    }
+    let root = repository.root;
+    let parser = args.log.parser()?;
+    let old = read_files(args.source, args.old, &root, &parser)?;
+    let new = read_files(args.source, args.new, &root, &parser)?;
+
+    // Files are iterated in path order, which is a depth-first-search order
+    // the tree writer needs.
+    let mut sink = TreeWriter::new(
+        args.format.backend(args.output, &Metadata::default())?,
+        None,
+        TreeOptions::default(),
+    )?;
+    let (mut added, mut removed, mut grown, mut shrunk) = (vec![], vec![], vec![], vec![]);
+    for (path, new_size) in &new {
+        let old_size = old.get(path).copied();
+        let growth = new_size.asize as i128 - old_size.unwrap_or_default().asize as i128;
+        match old_size {
+            None => added.push((path.as_path(), growth)),
+            Some(_) if growth > 0 => grown.push((path.as_path(), growth)),
+            Some(_) if growth < 0 => shrunk.push((path.as_path(), growth)),
+            Some(_) => {}
+        }
+        let old_size = old_size.unwrap_or_default();
+        if growth > 0 || new_size.dsize > old_size.dsize {
+            sink.entry(&Entry {
+                path,
+                is_dir: false,
+                included: true,
+                pattern: None,
+                size: Some(new_size.asize.saturating_sub(old_size.asize)),
+                stored_size: Some(new_size.dsize.saturating_sub(old_size.dsize)),
+                read_error: None,
+            })?;
+        }
+    }
+    for (path, old_size) in &old {
+        if !new.contains_key(path) {
+            removed.push((path.as_path(), -(old_size.asize as i128)));
+        }
+    }
+    Box::new(sink).finish()?;
+
+    print_top("Added", added, args.top);
+    print_top("Removed", removed, args.top);
+    print_top("Grown", grown, args.top);
+    print_top("Shrunk", shrunk, args.top);
+    Ok(())
+}
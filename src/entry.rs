@@ -0,0 +1,27 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+use anyhow::Result;
+use std::path::Path;
+
+/// File or directory visited by Duplicacy when enumerating the repository.
+pub struct Entry<'a> {
+    /// Path relative to the repository root, without trailing `/`.
+    pub path: &'a Path,
+    pub is_dir: bool,
+    pub included: bool,
+    /// Filter pattern that decided about inclusion, `None` if no pattern matched.
+    pub pattern: Option<&'a str>,
+    /// Size recorded by Duplicacy, when set the filesystem is not accessed.
+    pub size: Option<u64>,
+    /// Bytes the entry takes in the storage, when known.
+    pub stored_size: Option<u64>,
+    /// Why the entry, or the content of the directory, couldn't be read
+    /// while enumerating it.
+    pub read_error: Option<&'a str>,
+}
+
+/// Consumer of entries, they come in the order Duplicacy visits them.
+pub trait Sink {
+    fn entry(&mut self, entry: &Entry) -> Result<()>;
+    fn finish(self: Box<Self>) -> Result<()>;
+}
@@ -0,0 +1,297 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Reimplementation of the Duplicacy include/exclude patterns, see
+//! https://forum.duplicacy.com/t/filters-include-exclude-patterns/1089
+use anyhow::{Context, Result, bail};
+use regex::bytes::Regex;
+use std::path::{Path, PathBuf};
+
+enum Matcher {
+    Wildcard(String),
+    Regex(Regex),
+}
+
+struct Pattern {
+    /// Pattern as written in the filters file, e.g. `+foo/*` or `e:\.tmp$`.
+    text: String,
+    include: bool,
+    matcher: Matcher,
+}
+
+/// Result of matching a path against the filters.
+pub struct Match<'a> {
+    pub included: bool,
+    /// The pattern that decided about inclusion, `None` if no pattern matched.
+    pub pattern: Option<&'a str>,
+}
+
+#[derive(Default)]
+pub struct Filters {
+    patterns: Vec<Pattern>,
+}
+
+impl Filters {
+    /// Loads filters file, a missing file is the same as an empty one.
+    pub fn load(path: &Path) -> Result<Filters> {
+        let mut filters = Filters::default();
+        if path.exists() {
+            filters.load_file(path, &mut Vec::new())?;
+        }
+        Ok(filters)
+    }
+
+    fn load_file(&mut self, path: &Path, included: &mut Vec<PathBuf>) -> Result<()> {
+        let read = || -> Result<_> { Ok((path.canonicalize()?, std::fs::read_to_string(path)?)) };
+        let (canonical, contents) =
+            read().with_context(|| format!("failed to read pattern file {}", path.display()))?;
+        // Compared canonical, as the same file can be reached through `..`.
+        if included.contains(&canonical) {
+            bail!("pattern file {} is included recursively", path.display());
+        }
+        included.push(canonical);
+
+        for line in contents.lines() {
+            let line = line.trim();
+            if let Some(include_path) = line.strip_prefix('@') {
+                let include_path = include_path.trim();
+                if include_path.is_empty() {
+                    continue;
+                }
+                // Relative paths are resolved against the including file.
+                let include_path = path.parent().unwrap().join(include_path);
+                self.load_file(&include_path, included)?;
+                continue;
+            }
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.add_pattern(line)?;
+        }
+
+        included.pop();
+        Ok(())
+    }
+
+    fn add_pattern(&mut self, text: &str) -> Result<()> {
+        let (include, matcher) = if let Some(p) = text.strip_prefix('+') {
+            (true, Matcher::Wildcard(p.to_owned()))
+        } else if let Some(p) = text.strip_prefix('-') {
+            (false, Matcher::Wildcard(p.to_owned()))
+        } else if let Some(re) = text.strip_prefix("i:") {
+            (true, Matcher::Regex(compile_regex(re)?))
+        } else if let Some(re) = text.strip_prefix("e:") {
+            (false, Matcher::Regex(compile_regex(re)?))
+        } else {
+            // Patterns without a prefix are include patterns.
+            return self.add_pattern(&format!("+{text}"));
+        };
+        let empty = match &matcher {
+            Matcher::Wildcard(p) => p.is_empty(),
+            Matcher::Regex(re) => re.as_str().is_empty(),
+        };
+        if !empty {
+            self.patterns.push(Pattern {
+                text: text.to_owned(),
+                include,
+                matcher,
+            });
+        }
+        Ok(())
+    }
+
+    /// Inserts patterns before the pattern at `position`, counting patterns
+    /// from included files in the order they are evaluated.
+    pub fn insert(&mut self, position: usize, texts: &[String]) -> Result<()> {
+        if position > self.patterns.len() {
+            bail!(
+                "can't insert patterns at position {position}, there are only {} patterns",
+                self.patterns.len()
+            );
+        }
+        let rest = self.patterns.split_off(position);
+        for text in texts {
+            self.add_pattern(text)?;
+        }
+        self.patterns.extend(rest);
+        Ok(())
+    }
+
+    /// Matches path relative to the repository root. Directory paths must
+    /// end with `/`, the same as in Duplicacy.
+    pub fn matches(&self, path: &[u8]) -> Match<'_> {
+        for pattern in &self.patterns {
+            let matched = match &pattern.matcher {
+                Matcher::Wildcard(p) => match_wildcard(path, p.as_bytes()),
+                Matcher::Regex(re) => re.is_match(path),
+            };
+            if matched {
+                return Match {
+                    included: pattern.include,
+                    pattern: Some(&pattern.text),
+                };
+            }
+        }
+        // When nothing matched, the path is excluded only if there are
+        // exclusively include patterns.
+        Match {
+            included: self.default_included(),
+            pattern: None,
+        }
+    }
+
+    /// Whether paths not matched by any pattern are included.
+    pub fn default_included(&self) -> bool {
+        self.patterns.is_empty() || self.patterns.iter().any(|p| !p.include)
+    }
+
+    /// All patterns in the order they are evaluated.
+    pub fn patterns(&self) -> impl Iterator<Item = &str> {
+        self.patterns.iter().map(|p| p.text.as_str())
+    }
+}
+
+fn compile_regex(re: &str) -> Result<Regex> {
+    Regex::new(re).with_context(|| format!("invalid regex in pattern {re:?}"))
+}
+
+/// Matches whole `text` against `pattern` where `*` matches any sequence of
+/// characters, including `/`, and `?` matches any single character.
+pub fn match_wildcard(text: &[u8], pattern: &[u8]) -> bool {
+    let (mut t, mut p) = (0, 0);
+    // Position in pattern after last seen `*` and position in text it matched up to.
+    let mut backtrack = None;
+    while t < text.len() {
+        if p < pattern.len() && pattern[p] == b'*' {
+            p += 1;
+            backtrack = Some((p, t));
+        } else if p < pattern.len() && (pattern[p] == b'?' || pattern[p] == text[t]) {
+            p += 1;
+            t += 1;
+        } else if let Some((star_p, star_t)) = backtrack {
+            p = star_p;
+            t = star_t + 1;
+            backtrack = Some((star_p, t));
+        } else {
+            return false;
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    fn filters(patterns: &[&str]) -> Filters {
+        let mut filters = Filters::default();
+        for p in patterns {
+            filters.add_pattern(p).unwrap();
+        }
+        filters
+    }
+
+    fn decision<'a>(filters: &'a Filters, path: &str) -> (bool, Option<&'a str>) {
+        let m = filters.matches(path.as_bytes());
+        (m.included, m.pattern)
+    }
+
+    #[test]
+    fn wildcard() {
+        assert!(match_wildcard(b"foo", b"foo"));
+        assert!(!match_wildcard(b"foo/", b"foo"));
+        assert!(match_wildcard(b"", b"*"));
+        assert!(match_wildcard(b"a.txt", b"?.txt"));
+        assert!(!match_wildcard(b"ab.txt", b"?.txt"));
+        assert!(match_wildcard(b"a/b/c.tmp", b"*.tmp"));
+        assert!(match_wildcard(b"home/x/cache/", b"home/*/cache/"));
+        // `*` crosses directories, unlike in shell globs.
+        assert!(match_wildcard(b"home/x/y/cache/", b"home/*/cache/"));
+        assert!(match_wildcard(b"aXbXc", b"*b*c"));
+        assert!(!match_wildcard(b"aXbXd", b"*b*c"));
+        assert!(match_wildcard(b"abab", b"*ab"));
+        assert!(match_wildcard(b"x", b"x**"));
+    }
+
+    #[test]
+    fn first_match_wins() {
+        let f = filters(&["+docs/keep.tmp", "e:\\.tmp$", "i:^docs/", "-*"]);
+        assert_eq!(
+            decision(&f, "docs/keep.tmp"),
+            (true, Some("+docs/keep.tmp"))
+        );
+        assert_eq!(decision(&f, "docs/a.tmp"), (false, Some("e:\\.tmp$")));
+        assert_eq!(decision(&f, "docs/a.txt"), (true, Some("i:^docs/")));
+        assert_eq!(decision(&f, "other"), (false, Some("-*")));
+
+        let f = filters(&["i:\\.txt$", "e:^docs/"]);
+        assert_eq!(decision(&f, "docs/a.txt"), (true, Some("i:\\.txt$")));
+        assert_eq!(decision(&f, "docs/a.md"), (false, Some("e:^docs/")));
+    }
+
+    #[test]
+    fn pattern_without_prefix_includes() {
+        let f = filters(&["foo/", "", "-", "-bar/"]);
+        assert_eq!(f.patterns().collect::<Vec<_>>(), ["+foo/", "-bar/"]);
+        assert_eq!(decision(&f, "foo/"), (true, Some("+foo/")));
+    }
+
+    #[test]
+    fn default_inclusion() {
+        let f = filters(&[]);
+        assert_eq!(decision(&f, "a"), (true, None));
+        // Only include patterns exclude everything else.
+        let f = filters(&["+a/", "i:^b"]);
+        assert_eq!(decision(&f, "c"), (false, None));
+        // Any exclude pattern includes everything else.
+        let f = filters(&["+a/", "-b/"]);
+        assert_eq!(decision(&f, "c"), (true, None));
+        let f = filters(&["e:^b"]);
+        assert_eq!(decision(&f, "c"), (true, None));
+    }
+
+    #[test]
+    fn include_files() {
+        let dir = TempDir::new().unwrap();
+        let write = |name: &str, contents: &str| {
+            let path = dir.path().join(name);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, contents).unwrap();
+        };
+        write("filters", "+a\n@sub/common\n# comment\n-c\n@\n");
+        // Relative to the including file, not the top one.
+        write("sub/common", "  -b1 \n@more\n");
+        write("sub/more", "-b2\n");
+        let f = Filters::load(&dir.path().join("filters")).unwrap();
+        assert_eq!(f.patterns().collect::<Vec<_>>(), ["+a", "-b1", "-b2", "-c"]);
+
+        // A file can be included repeatedly, but not into itself.
+        write("filters", "@sub/more\n@sub/more\n");
+        let f = Filters::load(&dir.path().join("filters")).unwrap();
+        assert_eq!(f.patterns().count(), 2);
+        write("sub/more", "-b2\n@../filters\n");
+        let err = Filters::load(&dir.path().join("filters")).err().unwrap();
+        assert!(
+            format!("{err:#}").contains("included recursively"),
+            "{err:#}"
+        );
+
+        write("filters", "@missing\n");
+        assert!(Filters::load(&dir.path().join("filters")).is_err());
+        assert_eq!(
+            Filters::load(&dir.path().join("none"))
+                .unwrap()
+                .patterns()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn insert_patterns() {
+        let mut f = filters(&["+a", "-b"]);
+        f.insert(1, &["-x".to_owned(), "y".to_owned()]).unwrap();
+        assert_eq!(f.patterns().collect::<Vec<_>>(), ["+a", "-x", "+y", "-b"]);
+        assert!(f.insert(5, &["-z".to_owned()]).is_err());
+    }
+}
@@ -0,0 +1,32 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+use crate::entry::Entry;
+use crate::log::{for_each_line, split_dir};
+use anyhow::Result;
+use regex::bytes::Regex;
+use std::io::BufRead;
+
+/// Reads output of `duplicacy list -files -r N` and calls `f` with every
+/// file and directory stored in the revision.
+pub fn read_list(reader: impl BufRead, mut f: impl FnMut(&Entry) -> Result<()>) -> Result<()> {
+    // Each file is listed as size, modification time, hash and path, optionally
+    // prefixed with the log header when duplicacy is run with `-log`.
+    let file_re = Regex::new(r"^(?:\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3} INFO SNAPSHOT_FILE )?\s*(\d+) \d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2} (?:[0-9a-f]{64}| {64}) (?s-u:(.+))$").unwrap();
+
+    for_each_line(reader, |line| {
+        if let Some(caps) = file_re.captures(line) {
+            let (_, [size, path]) = caps.extract();
+            let (path, is_dir) = split_dir(path);
+            f(&Entry {
+                path,
+                is_dir,
+                included: true,
+                pattern: None,
+                size: Some(std::str::from_utf8(size)?.parse()?),
+                stored_size: None,
+                read_error: None,
+            })?;
+        }
+        Ok(())
+    })
+}
@@ -0,0 +1,163 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+use crate::entry::Entry;
+use anyhow::{Context, Result, bail};
+use clap::ValueEnum;
+use regex::bytes::Regex;
+use std::ffi::OsStr;
+use std::io::BufRead;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+
+/// Calls `f` with every line of `reader`, without the line terminator. Lines
+/// are bytes, as file names in them don't have to be valid UTF-8.
+pub fn for_each_line(
+    mut reader: impl BufRead,
+    mut f: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut line = Vec::new();
+    while reader.read_until(b'\n', &mut line)? > 0 {
+        let mut l = line.strip_suffix(b"\n").unwrap_or(&line);
+        l = l.strip_suffix(b"\r").unwrap_or(l);
+        f(l)?;
+        line.clear();
+    }
+    Ok(())
+}
+
+/// Splits path as printed by Duplicacy into path without trailing `/` and
+/// whether it's a directory.
+pub fn split_dir(path: &[u8]) -> (&Path, bool) {
+    match path.strip_suffix(b"/") {
+        Some(dir) => (Path::new(OsStr::from_bytes(dir)), true),
+        None => (Path::new(OsStr::from_bytes(path)), false),
+    }
+}
+
+/// Known layouts of log lines.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum LogFormat {
+    /// Duplicacy CLI run with `-log`
+    Cli,
+    /// Backup logs saved by Duplicacy Web, lines may have its own timestamp prefix
+    Web,
+}
+
+/// Options selecting how log lines are parsed.
+#[derive(clap::Args, Debug)]
+pub struct LogArgs {
+    /// Layout of the log lines
+    #[arg(long, value_enum, default_value_t = LogFormat::Cli)]
+    log_format: LogFormat,
+
+    /// Regex matching log lines about visited paths, overrides --log-format.
+    /// Group `path` captures the path and optional groups `decision`,
+    /// `pattern` and `size` capture `included` or `excluded`, the deciding
+    /// pattern and the file size in bytes
+    #[arg(long)]
+    line_regex: Option<String>,
+}
+
+impl LogArgs {
+    pub fn parser(&self) -> Result<LineParser> {
+        match &self.line_regex {
+            Some(re) => LineParser::from_regex(re),
+            None => Ok(LineParser::new(self.log_format)),
+        }
+    }
+}
+
+// Lines about every visited path when duplicacy is run with `-debug -log backup -enum-only`.
+// The log header is a timestamp and a level.
+const CLI_PREFIX: &str = r"^\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}(?:\.\d+)? \w+ ";
+// Duplicacy Web may prepend its own timestamp to lines of the CLI it runs.
+const WEB_PREFIX: &str =
+    r"^(?:\d{4}/\d{2}/\d{2} \d{2}:\d{2}:\d{2} )?\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}(?:\.\d+)? \w+ ";
+const MESSAGE: &str = r"PATTERN_(?:INCLUDE|EXCLUDE) (?s-u:(?<path>.*)) is (?<decision>included|excluded)(?: by pattern (?s-u:(?<pattern>.*)))?$";
+
+/// Extracts entries from log lines.
+pub struct LineParser {
+    re: Regex,
+    /// What the lines are expected to look like, for the warning about
+    /// nothing matching.
+    description: String,
+}
+
+impl LineParser {
+    pub fn new(format: LogFormat) -> Self {
+        let prefix = match format {
+            LogFormat::Cli => CLI_PREFIX,
+            LogFormat::Web => WEB_PREFIX,
+        };
+        LineParser {
+            re: Regex::new(&format!("{prefix}{MESSAGE}")).unwrap(),
+            description: format!(
+                "the `{}` log format",
+                format.to_possible_value().unwrap().get_name()
+            ),
+        }
+    }
+
+    pub fn from_regex(re: &str) -> Result<Self> {
+        let re = Regex::new(re).with_context(|| format!("invalid --line-regex {re:?}"))?;
+        if !re.capture_names().any(|name| name == Some("path")) {
+            bail!("--line-regex must have a `path` group");
+        }
+        Ok(LineParser {
+            re,
+            description: "--line-regex".to_owned(),
+        })
+    }
+}
+
+/// Reads log from `duplicacy -debug -log backup -enum-only` and calls `f`
+/// with every entry included or excluded by filters.
+pub fn read_log(
+    reader: impl BufRead,
+    parser: &LineParser,
+    mut f: impl FnMut(&Entry) -> Result<()>,
+) -> Result<()> {
+    let (mut lines, mut included) = (0, 0);
+    for_each_line(reader, |mut line| {
+        if lines == 0 {
+            // Logs saved by some Windows editors start with a byte order mark.
+            line = line.strip_prefix(b"\xef\xbb\xbf").unwrap_or(line);
+        }
+        lines += 1;
+        if let Some(caps) = parser.re.captures(line) {
+            let (path, is_dir) = split_dir(caps.name("path").unwrap().as_bytes());
+            let pattern = caps
+                .name("pattern")
+                .map(|m| String::from_utf8_lossy(m.as_bytes()));
+            let is_included = caps
+                .name("decision")
+                .is_none_or(|m| m.as_bytes() == b"included");
+            included += u64::from(is_included);
+            let size = match caps.name("size") {
+                Some(m) => Some(std::str::from_utf8(m.as_bytes())?.parse()?),
+                None => None,
+            };
+            f(&Entry {
+                path,
+                is_dir,
+                included: is_included,
+                pattern: pattern.as_deref(),
+                size,
+                stored_size: None,
+                read_error: None,
+            })?;
+        }
+        Ok(())
+    })?;
+    // An empty tree is most likely a log from a different Duplicacy version
+    // or run with wrong options, not a backup of nothing.
+    if included == 0 {
+        eprintln!(
+            "warning: none of {lines} log lines matched an included path with {}, the \
+             result is empty. Make sure duplicacy was run with `-log -debug backup -enum-only`, \
+             or pick the matching --log-format or --line-regex.",
+            parser.description
+        );
+    }
+    Ok(())
+}
@@ -1,136 +1,453 @@
 // SPDX-FileCopyrightText: 2025 Marek Rusinowski
 // SPDX-License-Identifier: Apache-2.0
-use anyhow::Result;
-use clap::{crate_name, crate_version, Parser};
+mod budget;
+mod diff;
+mod entry;
+mod filters;
+mod list;
+mod log;
+mod ncdu;
+mod patterns;
+mod report;
+mod repository;
+mod scan;
+mod storage;
+mod types;
+mod walk;
+mod whatif;
+
+use anyhow::{Result, bail};
+use budget::{Budget, BudgetExceeded, EXCEEDED_EXIT_CODE};
+use clap::{Parser, Subcommand, ValueEnum};
 use clio::{Input, Output};
-use regex::Regex;
-use serde::Serialize;
-use std::io::{BufRead, BufReader, BufWriter};
-use std::os::linux::fs::MetadataExt;
-use std::path::{Path, PathBuf};
-use std::time::SystemTime;
-use struson::writer::{JsonStreamWriter, JsonWriter};
+use entry::{Entry, Sink};
+use filters::Filters;
+use ncdu::bin::BinBackend;
+use ncdu::json::JsonBackend;
+use ncdu::{Backend, BufferedTreeWriter, Metadata, TreeOptions, TreeWriter};
+use patterns::PatternReport;
+use report::Report;
+use repository::Repository;
+use std::io::BufReader;
+use std::path::PathBuf;
+use std::process::ExitCode;
+use storage::{SharedChunks, Storage};
+use types::{GroupBy, TypeReport};
+use whatif::WhatIf;
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Source {
+    /// Log from `duplicacy -debug -log backup -enum-only`
+    Log,
+    /// Walk the repository applying `.duplicacy/filters` like Duplicacy does
+    Walk,
+    /// Output of `duplicacy list -files -r N`, doesn't access the filesystem
+    List,
+    /// Snapshot revision read from a local storage, doesn't access the repository
+    Storage,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+enum Format {
+    /// NCDU JSON Export
+    NcduJson,
+    /// NCDU binary export, requires NCDU >=2.6
+    NcduBin,
+}
+
+impl Format {
+    fn backend(self, output: Output, metadata: &Metadata) -> Result<Box<dyn Backend>> {
+        Ok(match self {
+            Format::NcduJson => Box::new(JsonBackend::new(output, metadata)?),
+            Format::NcduBin => Box::new(BinBackend::new(output)?),
+        })
+    }
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Write growth of files between two inputs and summary of the biggest changes
+    Diff(diff::DiffArgs),
+    /// Run `duplicacy backup -enum-only` in the repository and write what it would back up
+    Scan(scan::ScanArgs),
+}
 
 #[derive(Parser, Debug)]
-#[command(version, about, long_about = None)]
+#[command(version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Args {
-    /// Input with log from duplicacy
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Repository directory, by default found by looking for `.duplicacy` in
+    /// the current directory and its parents. Can be repeated to merge several
+    /// repositories into one export, each paired with its own input
+    #[arg(long)]
+    repository: Vec<PathBuf>,
+
+    /// Input with log or file listing from duplicacy, can be repeated
     #[arg(short, long, default_value = "-")]
-    input: Input,
+    input: Vec<Input>,
+
+    #[command(flatten)]
+    log: log::LogArgs,
+
+    /// Never access the filesystem, sizes come only from the input and
+    /// metadata that isn't in it is left out. For inputs from other machines,
+    /// the repository is not looked for unless given with --repository
+    #[arg(long)]
+    offline: bool,
+
+    /// Where to get the list of backed up files from
+    #[arg(short, long, value_enum, default_value_t = Source::Log)]
+    source: Source,
+
+    /// Local storage directory to read the snapshot from
+    #[arg(long, required_if_eq("source", "storage"))]
+    storage: Option<PathBuf>,
+
+    /// Snapshot id to read from the storage, needed if there is more than one
+    #[arg(long)]
+    snapshot_id: Option<String>,
+
+    /// Snapshot revision to read from the storage, the latest by default
+    #[arg(short, long)]
+    revision: Option<u32>,
+
+    /// How the size of chunks shared by multiple files is attributed to them
+    #[arg(long, value_enum, default_value_t = SharedChunks::Split)]
+    shared_chunks: SharedChunks,
 
-    /// Output to write NCDU Json Export
+    /// RSA private key file, needed for storages with RSA encryption enabled
+    #[arg(long)]
+    key: Option<PathBuf>,
+
+    #[command(flatten)]
+    export: ExportArgs,
+}
+
+/// Options of the written result, shared by modes that read a single set of
+/// backed up files.
+#[derive(clap::Args, Debug)]
+struct ExportArgs {
+    /// Output to write NCDU Export
     #[arg(short, long, default_value = "-")]
     output: Output,
+
+    /// Format of the NCDU Export
+    #[arg(short, long, value_enum, default_value_t = Format::NcduJson)]
+    format: Format,
+
+    /// Also show files and directories excluded by filters, marked as excluded
+    #[arg(short = 'x', long)]
+    show_excluded: bool,
+
+    /// Leave out files that vanished or can't be stat'ed instead of marking
+    /// them as read errors
+    #[arg(long)]
+    skip_unreadable: bool,
+
+    /// Append the target path to names of symlinks in the repository root
+    /// that Duplicacy follows
+    #[arg(long)]
+    annotate_links: bool,
+
+    /// Also write owner, mode and modification time of files, shown by
+    /// `ncdu --extended`
+    #[arg(short, long)]
+    extended: bool,
+
+    /// Build the whole tree in memory before writing it, needed when the input
+    /// isn't in the order Duplicacy visits files, e.g. sorted or merged logs
+    #[arg(long)]
+    buffered: bool,
+
+    /// Instead of NCDU Export, write how much every filter pattern includes and excludes
+    #[arg(long, conflicts_with_all = ["report", "by_type"])]
+    pattern_report: bool,
+
+    /// Instead of NCDU Export, write the total size and the largest
+    /// directories and files as text
+    #[arg(long, conflicts_with = "by_type")]
+    report: bool,
+
+    /// Depth of directories listed by `--report`, 1 is the repository root
+    /// children
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u64).range(1..))]
+    report_depth: u64,
+
+    /// Number of directories and files listed by `--report`, or file types
+    /// by `--by-type`
+    #[arg(short = 'n', long, default_value_t = 10)]
+    top: usize,
+
+    /// Instead of NCDU Export, write the number of files and bytes of every
+    /// file type
+    #[arg(long, value_enum)]
+    by_type: Option<GroupBy>,
+
+    /// With `--by-type`, write NCDU Export with a top-level directory for
+    /// every file type instead
+    #[arg(long, requires = "by_type")]
+    type_tree: bool,
+
+    /// Candidate filter pattern to evaluate, can be repeated. Instead of the
+    /// usual NCDU Export, write one with files the changed filters would
+    /// newly exclude or include, and print their totals
+    #[arg(long, value_name = "PATTERN", allow_hyphen_values = true)]
+    #[arg(conflicts_with_all = ["pattern_report", "report", "by_type"])]
+    what_if: Vec<String>,
+
+    /// Number of current filter patterns evaluated before the `--what-if`
+    /// ones, by default they go first
+    #[arg(long, default_value_t = 0, requires = "what_if")]
+    what_if_at: usize,
+
+    /// Instead of NCDU Export, check the size and file count limits from the
+    /// rules file and write the exceeded ones. Exits with status 3 if there
+    /// are any
+    #[arg(long, value_name = "RULES")]
+    #[arg(conflicts_with_all = ["pattern_report", "report", "by_type", "what_if"])]
+    budget: Option<PathBuf>,
 }
 
-#[derive(Serialize)]
-struct FileInfo<'a> {
-    name: &'a str,
-    asize: u64,
-    dsize: u64,
-    dev: u64,
-    ino: u64,
-    nlink: u64,
-    notreg: bool,
+impl ExportArgs {
+    /// Creates sink writing the result for `repositories`. With `names`, they
+    /// are merged under a synthetic root, one top-level directory per name.
+    fn sink(
+        self,
+        repositories: &[Repository],
+        names: &[PathBuf],
+        uses_fs: bool,
+    ) -> Result<Box<dyn Sink>> {
+        if self.pattern_report {
+            return Ok(Box::new(PatternReport::new(
+                self.output,
+                Filters::load(&repositories[0].filters_path())?,
+                uses_fs.then_some(repositories[0].root.as_path()),
+            )));
+        }
+        // Paths of entries are prefixed with the name when merged, the same
+        // as the roots to stat them in.
+        let mut roots = Vec::new();
+        if uses_fs {
+            let repository_roots = repositories.iter().map(|r| r.root.clone());
+            match names {
+                [] => roots.extend(repository_roots.map(|root| (PathBuf::new(), root))),
+                _ => roots.extend(names.iter().cloned().zip(repository_roots)),
+            }
+        }
+        if let Some(rules) = &self.budget {
+            return Ok(Box::new(Budget::new(self.output, roots, rules)?));
+        }
+        if self.report {
+            let depth = self.report_depth as usize;
+            return Ok(Box::new(Report::new(self.output, roots, depth, self.top)));
+        }
+        if !self.what_if.is_empty() {
+            let mut filters = Filters::load(&repositories[0].filters_path())?;
+            filters.insert(self.what_if_at, &self.what_if)?;
+            // The export has both files that go out and in, placed under
+            // their own top-level directories.
+            let backend = self.format.backend(self.output, &Metadata::default())?;
+            let tree = TreeWriter::merged(backend, Vec::new(), TreeOptions::default())?;
+            let tree = BufferedTreeWriter::new(tree);
+            let root = uses_fs.then_some(repositories[0].root.as_path());
+            return Ok(Box::new(WhatIf::new(filters, root, tree)));
+        }
+        if let Some(group_by) = self.by_type {
+            if matches!(group_by, GroupBy::Content) && roots.is_empty() {
+                bail!("--by-type content reads the files, it needs the filesystem");
+            }
+            if !self.type_tree {
+                return Ok(Box::new(TypeReport::new(
+                    self.output,
+                    roots,
+                    group_by,
+                    self.top,
+                )));
+            }
+            // Types are the top-level directories, so the tree is merged
+            // from them, and sizes always come with the entries.
+            let backend = self.format.backend(self.output, &Metadata::default())?;
+            let tree = TreeWriter::merged(backend, Vec::new(), TreeOptions::default())?;
+            let tree = BufferedTreeWriter::new(tree);
+            return Ok(Box::new(TypeReport::tree(tree, roots, group_by)));
+        }
+        let metadata = match repositories {
+            // Only a found repository is worth recording, not whatever
+            // directory we were run in.
+            [repository] => Metadata {
+                repository: repository.top.as_ref().map(|_| repository.root.as_path()),
+                backup_id: repository.backup_id.as_deref(),
+            },
+            _ => Metadata::default(),
+        };
+        let backend = self.format.backend(self.output, &metadata)?;
+        let options = TreeOptions {
+            show_excluded: self.show_excluded,
+            skip_unreadable: self.skip_unreadable,
+            annotate_links: self.annotate_links,
+            extended: self.extended,
+        };
+        let writer = if names.is_empty() {
+            let fs_root = uses_fs.then_some(repositories[0].root.as_path());
+            TreeWriter::new(backend, fs_root, options)?
+        } else {
+            TreeWriter::merged(backend, roots, options)?
+        };
+        if self.buffered {
+            Ok(Box::new(BufferedTreeWriter::new(writer)))
+        } else {
+            Ok(Box::new(writer))
+        }
+    }
 }
 
-#[derive(Serialize)]
-struct NcduMetadata {
-    progname: &'static str,
-    progver: &'static str,
-    timestamp: u64,
+/// Passphrase of an encrypted RSA private key, taken the same way as the
+/// storage password.
+fn rsa_passphrase() -> Result<String> {
+    match std::env::var("DUPLICACY_RSA_PASSPHRASE") {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => Ok(rpassword::prompt_password("Enter RSA key passphrase: ")?),
+    }
 }
 
-fn write_infoblock<J: JsonWriter>(json_writer: &mut J, path: &Path) -> Result<()> {
-    let meta = std::fs::symlink_metadata(path)?;
-    let name = if path.is_absolute() {
-        path.to_str().unwrap()
-    } else {
-        path.file_name().unwrap().to_str().unwrap()
-    };
-    json_writer.serialize_value(&FileInfo {
-        name,
-        asize: meta.st_size(),
-        dsize: meta.st_blocks() * 512,
-        dev: meta.st_dev(),
-        ino: meta.st_ino(),
-        nlink: meta.st_nlink(),
-        notreg: !meta.is_dir() && !meta.is_file(),
-    })?;
-    Ok(())
+/// Password of an encrypted storage, it's never accepted on the command line
+/// so it doesn't leak through the process list or shell history.
+fn storage_password() -> Result<String> {
+    match std::env::var("DUPLICACY_PASSWORD") {
+        Ok(password) => Ok(password),
+        Err(_) => Ok(rpassword::prompt_password("Enter storage password: ")?),
+    }
 }
 
-fn main() -> Result<()> {
-    let args = Args::parse();
-    let reader = BufReader::new(args.input);
-
-    // The format of file inclusion lines when duplicacy is run with `-debug -log backup -enum-only`
-    let include_re = Regex::new(r"^\d{4}-\d{2}-\d{2} \d{2}:\d{2}:\d{2}.\d{3} DEBUG PATTERN_INCLUDE (.*) is included(?: by pattern .*)?$").unwrap();
-
-    let mut json_writer = JsonStreamWriter::new(BufWriter::new(args.output));
-
-    json_writer.begin_array()?;
-    // Format compatible with NCDU >=1.16
-    json_writer.number_value(1)?;
-    json_writer.number_value(2)?;
-    json_writer.serialize_value(&NcduMetadata {
-        progname: crate_name!(),
-        progver: crate_version!(),
-        timestamp: SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-    })?;
-
-    json_writer.begin_array()?;
-    write_infoblock(&mut json_writer, std::env::current_dir()?.as_path())?;
-
-    // Holds current stack of open directories to open and close corresponding
-    // json arrays as we stream through files included by duplicacy. We can do
-    // this because Duplicacy visits files in a depth-first-search order.
-    let mut dir = PathBuf::new();
-
-    for line_or in reader.lines() {
-        let line = line_or?;
-        if let Some(caps) = include_re.captures(line.as_str()) {
-            let (_, [path_str]) = caps.extract();
-
-            // We ignore all directories, we care only about files
-            if path_str.ends_with("/") {
-                continue;
+fn main() -> Result<ExitCode> {
+    match run() {
+        Ok(()) => Ok(ExitCode::SUCCESS),
+        Err(err) => match err.downcast::<BudgetExceeded>() {
+            Ok(exceeded) => {
+                eprintln!("{exceeded}");
+                Ok(ExitCode::from(EXCEEDED_EXIT_CODE))
             }
-            let path = Path::new(path_str);
+            Err(err) => Err(err),
+        },
+    }
+}
 
-            // Get to the common ancestor of previously handled file and current one.
-            while !path.starts_with(dir.as_path()) {
-                dir.pop();
-                json_writer.end_array()?;
-            }
+fn run() -> Result<()> {
+    let args = Args::parse();
+    match args.command {
+        Some(Command::Diff(diff_args)) => return diff::run(diff_args),
+        Some(Command::Scan(scan_args)) => return scan::run(scan_args),
+        None => {}
+    }
+    let repositories = if args.repository.is_empty() && args.offline {
+        // Whatever repository we happen to be run in has nothing to do with
+        // an input from another machine, so it's not looked for.
+        vec![Repository::unknown(std::env::current_dir()?)]
+    } else if args.repository.is_empty() {
+        vec![Repository::find(None)?]
+    } else {
+        let repositories = args.repository.iter().map(|p| Repository::find(Some(p)));
+        repositories.collect::<Result<Vec<_>>>()?
+    };
+    // File listing and storage describe a stored revision, not the current
+    // state of the filesystem.
+    let uses_fs = match args.source {
+        Source::Walk if args.offline => {
+            bail!("--source walk reads the filesystem, it can't be --offline")
+        }
+        _ if args.offline => false,
+        Source::List | Source::Storage => false,
+        Source::Log | Source::Walk => {
+            repositories.iter().for_each(Repository::warn_if_not_found);
+            true
+        }
+    };
 
-            // Open all directories from common ancestor to the parent of current file.
-            for c in path
-                .strip_prefix(dir.as_path())
-                .unwrap()
-                .parent()
-                .unwrap()
-                .components()
-            {
-                dir.push(c);
-                json_writer.begin_array()?;
-                write_infoblock(&mut json_writer, dir.as_path())?;
+    // With several repositories, each one becomes a top-level directory
+    // named after its backup ID.
+    let merged = repositories.len() > 1 || args.input.len() > 1;
+    let mut names = Vec::new();
+    if merged {
+        match args.source {
+            Source::Log | Source::List if args.input.len() != repositories.len() => {
+                bail!("every --input needs its own --repository")
             }
-
-            // Finally dump information about currently handled file.
-            write_infoblock(&mut json_writer, path)?;
+            Source::Storage => bail!("--source storage reads a single snapshot"),
+            _ if args.export.pattern_report => {
+                bail!("--pattern-report works with a single repository")
+            }
+            _ if !args.export.what_if.is_empty() => {
+                bail!("--what-if works with a single repository")
+            }
+            _ => {}
+        }
+        for repository in &repositories {
+            let name = match &repository.backup_id {
+                Some(id) => PathBuf::from(id),
+                None => PathBuf::from(repository.root.file_name().unwrap_or_default()),
+            };
+            if names.contains(&name) {
+                bail!(
+                    "backup ID {} is used by more than one repository",
+                    name.display()
+                );
+            }
+            names.push(name);
         }
     }
 
-    while dir.pop() {
-        json_writer.end_array()?;
+    let parser = args.log.parser()?;
+    let mut sink = args.export.sink(&repositories, &names, uses_fs)?;
+
+    let mut inputs = args.input.into_iter();
+    let mut missing_size = false;
+    for (i, repository) in repositories.iter().enumerate() {
+        let prefix = names.get(i).cloned().unwrap_or_default();
+        let mut f = |e: &Entry| {
+            if args.offline && e.included && !e.is_dir && e.size.is_none() && !missing_size {
+                missing_size = true;
+                eprintln!(
+                    "warning: the input has no file sizes and --offline can't stat the files, \
+                     they are counted as 0 bytes. Use a --line-regex with a `size` group, or a \
+                     file listing"
+                );
+            }
+            sink.entry(&Entry {
+                path: &prefix.join(e.path),
+                ..*e
+            })
+        };
+        match args.source {
+            Source::Log => {
+                let input = BufReader::new(inputs.next().unwrap());
+                log::read_log(input, &parser, &mut f)?
+            }
+            Source::List => list::read_list(BufReader::new(inputs.next().unwrap()), &mut f)?,
+            Source::Storage => {
+                let storage = Storage::open(
+                    args.storage.as_deref().unwrap(),
+                    storage_password,
+                    args.key.as_deref(),
+                    rsa_passphrase,
+                )?;
+                let id = match &args.snapshot_id {
+                    Some(id) => id.clone(),
+                    None => storage.only_snapshot_id()?,
+                };
+                let revision = match args.revision {
+                    Some(revision) => revision,
+                    None => storage.latest_revision(&id)?,
+                };
+                storage.read_snapshot(&id, revision, args.shared_chunks, &mut f)?;
+            }
+            Source::Walk => {
+                let filters = Filters::load(&repository.filters_path())?;
+                walk::walk(&repository.root, &filters, &mut f)?;
+            }
+        }
     }
 
-    json_writer.end_array()?;
-    json_writer.end_array()?;
-    json_writer.finish_document()?.into_inner()?.finish()?;
-    Ok(())
+    sink.finish()
 }
@@ -0,0 +1,687 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Writing of the NCDU export formats.
+use crate::entry::{Entry, Sink};
+use crate::repository::locate;
+use crate::walk::followed_link;
+use anyhow::Result;
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::ops::Bound;
+use std::os::linux::fs::MetadataExt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Component, Path, PathBuf};
+
+pub mod bin;
+pub mod json;
+
+pub struct FileInfo<'a> {
+    pub name: Cow<'a, OsStr>,
+    pub asize: u64,
+    pub dsize: u64,
+    pub dev: Option<u64>,
+    pub ino: Option<u64>,
+    pub nlink: Option<u64>,
+    pub notreg: bool,
+    pub read_error: bool,
+    pub excluded: Option<&'static str>,
+    pub extended: Option<Extended>,
+}
+
+/// Extended information, NCDU shows it when run with `--extended`.
+#[derive(Clone, Copy)]
+pub struct Extended {
+    pub uid: u32,
+    pub gid: u32,
+    pub mode: u32,
+    /// Modification time in seconds since the Unix epoch.
+    pub mtime: u64,
+}
+
+/// Information about the export as a whole.
+#[derive(Default)]
+pub struct Metadata<'a> {
+    pub repository: Option<&'a Path>,
+    pub backup_id: Option<&'a str>,
+}
+
+/// Export format specific writer. Directories are opened before and closed
+/// after all their children, the first opened directory is the root.
+pub trait Backend {
+    fn open_dir(&mut self, info: &FileInfo) -> Result<()>;
+    fn close_dir(&mut self) -> Result<()>;
+    /// Writes an entry without children: a file or an excluded directory.
+    fn file(&mut self, info: &FileInfo) -> Result<()>;
+    fn finish(self: Box<Self>) -> Result<()>;
+}
+
+impl<'a> FileInfo<'a> {
+    /// Metadata of the file at `path`, or of its target with `follow`.
+    fn stat(
+        path: &Path,
+        name: &'a OsStr,
+        excluded: bool,
+        follow: bool,
+        extended: bool,
+    ) -> Result<Self> {
+        let meta = if follow {
+            std::fs::metadata(path)?
+        } else {
+            std::fs::symlink_metadata(path)?
+        };
+        Ok(FileInfo {
+            name: Cow::Borrowed(name),
+            asize: meta.st_size(),
+            dsize: meta.st_blocks() * 512,
+            dev: Some(meta.st_dev()),
+            ino: Some(meta.st_ino()),
+            nlink: Some(meta.st_nlink()),
+            notreg: !meta.is_dir() && !meta.is_file(),
+            read_error: false,
+            excluded: excluded.then_some("pattern"),
+            extended: extended.then(|| Extended {
+                uid: meta.st_uid(),
+                gid: meta.st_gid(),
+                mode: meta.st_mode(),
+                // Files from before the epoch are shown as from the epoch.
+                mtime: meta.st_mtime().max(0) as u64,
+            }),
+        })
+    }
+
+    /// Entry that isn't backed by the filesystem, e.g. from a snapshot listing.
+    fn recorded(name: &'a OsStr, size: u64, stored_size: Option<u64>, excluded: bool) -> Self {
+        FileInfo {
+            name: Cow::Borrowed(name),
+            asize: size,
+            // Without the stored size, the apparent size is the closest
+            // approximation of the disk usage.
+            dsize: stored_size.unwrap_or(size),
+            dev: None,
+            ino: None,
+            nlink: None,
+            notreg: false,
+            read_error: false,
+            excluded: excluded.then_some("pattern"),
+            extended: None,
+        }
+    }
+
+    /// Whether the entry is a file with more than one hard link, NCDU counts
+    /// every such inode only once.
+    fn is_hard_link(&self) -> bool {
+        self.excluded.is_none() && self.ino.is_some() && self.nlink.is_some_and(|n| n > 1)
+    }
+
+    /// Entry that couldn't be stat'ed, e.g. because it vanished after
+    /// Duplicacy enumerated it.
+    fn unreadable(name: &'a OsStr) -> Self {
+        FileInfo {
+            read_error: true,
+            ..FileInfo::recorded(name, 0, None, false)
+        }
+    }
+}
+
+/// Settings of the written tree.
+#[derive(Default)]
+pub struct TreeOptions {
+    /// Also write entries excluded by filters, marked as excluded.
+    pub show_excluded: bool,
+    /// Leave entries that couldn't be stat'ed out instead of writing them
+    /// as read errors.
+    pub skip_unreadable: bool,
+    /// Append target path to names of followed symlinks in the repository root.
+    pub annotate_links: bool,
+    /// Also write owner, mode and modification time of stat'ed entries.
+    pub extended: bool,
+}
+
+/// Included directory recorded by [`TreeWriter`] before it is written.
+struct PendingDir {
+    size: Option<u64>,
+    stored_size: Option<u64>,
+    read_error: Option<String>,
+}
+
+/// Streams NCDU Export out of files visited in a depth-first-search order.
+pub struct TreeWriter {
+    backend: Box<dyn Backend>,
+    options: TreeOptions,
+    /// Prefix of paths in the tree and repository root on the filesystem
+    /// they are relative to. Empty when the filesystem must not be accessed
+    /// and all sizes come from entries.
+    roots: Vec<(PathBuf, PathBuf)>,
+    /// Paths that couldn't be stat'ed with the reason.
+    errors: Vec<(PathBuf, String)>,
+    /// Device and inode of every hard linked file written.
+    links: HashSet<(u64, u64)>,
+    /// Number of hard links to already written inodes and their disk usage.
+    extra_links: u64,
+    extra_links_bytes: u64,
+
+    // Holds current stack of open directories to open and close corresponding
+    // directories in the backend as we stream through files.
+    dir: PathBuf,
+    /// Directory closed last in the backend. Siblings come sorted, so opening
+    /// one that doesn't sort after it means the input is out of order and
+    /// directories may be written twice.
+    last_closed: PathBuf,
+    /// Entry written last with [`Backend::file`], entries after it in the
+    /// same directory must sort after it.
+    last_file: PathBuf,
+    /// Number of entries that came out of the depth-first order.
+    out_of_order: u64,
+    /// Included directories not opened yet with their recorded sizes. They
+    /// are opened once something inside them comes, the ones still here when
+    /// their parent is closed are empty.
+    pending: BTreeMap<PathBuf, PendingDir>,
+    empty_dirs: u64,
+}
+
+impl TreeWriter {
+    /// Tree of a single repository, with root on the filesystem at `root`.
+    pub fn new(
+        backend: Box<dyn Backend>,
+        root: Option<&Path>,
+        options: TreeOptions,
+    ) -> Result<Self> {
+        let roots = root.map(|root| vec![(PathBuf::new(), root.to_owned())]);
+        Self::with_roots(backend, roots.unwrap_or_default(), options)
+    }
+
+    /// Tree with a synthetic root and a top-level directory for every
+    /// repository. Entries of a repository must have paths prefixed with the
+    /// name of its directory. `repositories` pairs these names with roots on
+    /// the filesystem and is empty if it must not be accessed.
+    pub fn merged(
+        backend: Box<dyn Backend>,
+        repositories: Vec<(PathBuf, PathBuf)>,
+        options: TreeOptions,
+    ) -> Result<Self> {
+        Self::with_roots(backend, repositories, options)
+    }
+
+    fn with_roots(
+        mut backend: Box<dyn Backend>,
+        roots: Vec<(PathBuf, PathBuf)>,
+        options: TreeOptions,
+    ) -> Result<Self> {
+        match roots.as_slice() {
+            [(prefix, root)] if prefix.as_os_str().is_empty() => {
+                let info = FileInfo::stat(root, root.as_os_str(), false, false, options.extended)?;
+                backend.open_dir(&info)?
+            }
+            _ => backend.open_dir(&FileInfo::recorded(OsStr::new("/"), 0, None, false))?,
+        }
+        Ok(TreeWriter {
+            backend,
+            options,
+            roots,
+            errors: Vec::new(),
+            links: HashSet::new(),
+            extra_links: 0,
+            extra_links_bytes: 0,
+            dir: PathBuf::new(),
+            last_closed: PathBuf::new(),
+            last_file: PathBuf::new(),
+            out_of_order: 0,
+            pending: BTreeMap::new(),
+            empty_dirs: 0,
+        })
+    }
+
+    /// Whether the entry ends up in the export.
+    fn wants(&self, entry: &Entry) -> bool {
+        // Excluded directories are not descended into, so they are shown as
+        // a whole.
+        entry.included || self.options.show_excluded
+    }
+
+    /// Info of a directory that may have been recorded as pending.
+    fn dir_info<'a>(&mut self, path: &'a Path) -> Result<FileInfo<'a>> {
+        let Some(dir) = self.pending.remove(path) else {
+            return self.info(path, None);
+        };
+        let entry = Entry {
+            path,
+            is_dir: true,
+            included: true,
+            pattern: None,
+            size: dir.size,
+            stored_size: dir.stored_size,
+            read_error: dir.read_error.as_deref(),
+        };
+        self.info(path, Some(&entry))
+    }
+
+    /// Writes pending directories under `dir`, nothing else came inside them.
+    fn flush_pending(&mut self, dir: &Path) -> Result<()> {
+        let dirs: Vec<PathBuf> = self
+            .pending
+            .range::<Path, _>((Bound::Excluded(dir), Bound::Unbounded))
+            .map(|(path, _)| path)
+            .take_while(|path| path.starts_with(dir))
+            .cloned()
+            .collect();
+        let mut open: Vec<&Path> = Vec::new();
+        for (i, path) in dirs.iter().enumerate() {
+            while open.last().is_some_and(|d| !path.starts_with(d)) {
+                open.pop();
+                self.backend.close_dir()?;
+            }
+            // Unreadable directories are not known to be empty.
+            if dirs.get(i + 1).is_none_or(|next| !next.starts_with(path))
+                && self.pending[path].read_error.is_none()
+            {
+                self.empty_dirs += 1;
+            }
+            let info = self.dir_info(path)?;
+            self.backend.open_dir(&info)?;
+            open.push(path);
+        }
+        for _ in open {
+            self.backend.close_dir()?;
+        }
+        Ok(())
+    }
+
+    /// Closes the innermost open directory.
+    fn close_dir(&mut self) -> Result<()> {
+        let dir = self.dir.clone();
+        self.flush_pending(&dir)?;
+        self.last_closed = dir;
+        self.dir.pop();
+        self.backend.close_dir()
+    }
+
+    /// Info of the entry, or of the directory at `path` when there is no entry.
+    /// Failures to stat are recorded and the entry is marked as a read error.
+    fn info<'a>(&mut self, path: &'a Path, entry: Option<&Entry>) -> Result<FileInfo<'a>> {
+        let name = path.file_name().unwrap();
+        if let Some(err) = entry.and_then(|e| e.read_error) {
+            self.errors.push((path.to_owned(), err.to_owned()));
+            return Ok(FileInfo::unreadable(name));
+        }
+        let (size, stored_size) = entry.map_or((None, None), |e| (e.size, e.stored_size));
+        let excluded = entry.is_some_and(|e| !e.included);
+        match (size, locate(&self.roots, path)) {
+            (Some(size), _) => Ok(FileInfo::recorded(name, size, stored_size, excluded)),
+            (None, Some((root, relative))) => {
+                // Followed links look like directories with target metadata,
+                // the same as Duplicacy stores them.
+                let target = followed_link(root, relative);
+                let full_path = root.join(relative);
+                let extended = self.options.extended;
+                match FileInfo::stat(&full_path, name, excluded, target.is_some(), extended) {
+                    Ok(mut info) => {
+                        if let Some(target) = target
+                            && self.options.annotate_links
+                        {
+                            let mut name = OsString::from(name);
+                            name.push(" -> ");
+                            name.push(target);
+                            info.name = Cow::Owned(name);
+                        }
+                        Ok(info)
+                    }
+                    Err(err) => {
+                        self.errors.push((path.to_owned(), err.to_string()));
+                        Ok(FileInfo::unreadable(name))
+                    }
+                }
+            }
+            (None, None) => Ok(FileInfo::recorded(name, 0, None, excluded)),
+        }
+    }
+
+    fn warn_out_of_order(&mut self, what: String) {
+        self.out_of_order += 1;
+        eprintln!(
+            "warning: {what}, the input is not in depth-first order and entries may be written \
+             twice, use --buffered"
+        );
+    }
+
+    /// Adds file with path relative to the root, it must come in DFS order.
+    /// Excluded entries can also be directories, they are written without
+    /// any children.
+    fn add(&mut self, entry: &Entry) -> Result<()> {
+        let path = entry.path;
+        // Get to the common ancestor of previously handled file and current one.
+        while !path.starts_with(self.dir.as_path()) {
+            self.close_dir()?;
+        }
+
+        // Open all directories from common ancestor to the parent of current file.
+        for c in path
+            .strip_prefix(self.dir.as_path())
+            .unwrap()
+            .parent()
+            .unwrap()
+            .components()
+        {
+            self.dir.push(c);
+            if self.dir.parent() == self.last_closed.parent()
+                && !sorts_after(&self.dir, &self.last_closed)
+            {
+                self.warn_out_of_order(format!(
+                    "{} comes after {} was finished",
+                    self.dir.display(),
+                    self.last_closed.display()
+                ));
+            }
+            let dir = self.dir.clone();
+            // Directories with unreadable metadata are still written, they
+            // hold the children.
+            let info = self.dir_info(&dir)?;
+            self.backend.open_dir(&info)?;
+        }
+
+        // Finally dump information about currently handled file.
+        if path.parent() == self.last_file.parent() && !sorts_after(path, &self.last_file) {
+            self.warn_out_of_order(format!(
+                "{} comes after {} was written",
+                path.display(),
+                self.last_file.display()
+            ));
+        }
+        self.last_file = path.to_owned();
+        let info = self.info(path, Some(entry))?;
+        if info.read_error && self.options.skip_unreadable {
+            return Ok(());
+        }
+        if info.is_hard_link()
+            && !self
+                .links
+                .insert((info.dev.unwrap_or_default(), info.ino.unwrap()))
+        {
+            self.extra_links += 1;
+            self.extra_links_bytes += info.dsize;
+        }
+        self.backend.file(&info)
+    }
+}
+
+/// Whether directory `a` comes after its sibling `b`, comparing names either
+/// plainly or with `/` appended, as Duplicacy orders directories.
+fn sorts_after(a: &Path, b: &Path) -> bool {
+    let (a, b) = (a.as_os_str().as_bytes(), b.as_os_str().as_bytes());
+    a > b || a.iter().chain(b"/").gt(b.iter().chain(b"/"))
+}
+
+/// Path relative to the root without `.`, `..` and leading `/`, which paths
+/// captured by a custom --line-regex may have. `None` for the root itself
+/// and paths outside of it.
+fn normalize(path: &Path) -> Option<Cow<'_, Path>> {
+    if path.components().all(|c| matches!(c, Component::Normal(_))) {
+        return (!path.as_os_str().is_empty()).then_some(Cow::Borrowed(path));
+    }
+    let mut normal = PathBuf::new();
+    for c in path.components() {
+        match c {
+            Component::Normal(name) => normal.push(name),
+            Component::ParentDir if !normal.pop() => return None,
+            _ => {}
+        }
+    }
+    (!normal.as_os_str().is_empty()).then_some(Cow::Owned(normal))
+}
+
+impl Sink for TreeWriter {
+    fn entry(&mut self, entry: &Entry) -> Result<()> {
+        let Some(path) = normalize(entry.path) else {
+            eprintln!(
+                "warning: skipping {:?}, it's not a path inside the repository",
+                entry.path
+            );
+            return Ok(());
+        };
+        let entry = &Entry {
+            path: &path,
+            ..*entry
+        };
+        if !self.wants(entry) {
+            Ok(())
+        } else if entry.included && entry.is_dir {
+            // Already opened for what came inside it before.
+            if self.dir.starts_with(entry.path) {
+                self.warn_out_of_order(format!(
+                    "{} comes after what is inside it",
+                    entry.path.display()
+                ));
+                return Ok(());
+            }
+            self.pending.insert(
+                entry.path.to_owned(),
+                PendingDir {
+                    size: entry.size,
+                    stored_size: entry.stored_size,
+                    read_error: entry.read_error.map(str::to_owned),
+                },
+            );
+            Ok(())
+        } else {
+            self.add(entry)
+        }
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        while !self.dir.as_os_str().is_empty() {
+            self.close_dir()?;
+        }
+        // Root directory.
+        self.flush_pending(Path::new(""))?;
+        self.backend.close_dir()?;
+        self.backend.finish()?;
+        if self.empty_dirs > 0 {
+            eprintln!("{} empty directories", self.empty_dirs);
+        }
+        if self.out_of_order > 0 {
+            eprintln!("{} entries out of depth-first order", self.out_of_order);
+        }
+        if self.extra_links > 0 {
+            eprintln!(
+                "{} hard links to files already counted, saving {} bytes",
+                self.extra_links, self.extra_links_bytes
+            );
+        }
+        if !self.errors.is_empty() {
+            eprintln!("{} entries couldn't be read:", self.errors.len());
+            for (path, err) in &self.errors {
+                eprintln!("  {}: {err}", path.display());
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Entry kept in memory by [`BufferedTreeWriter`].
+struct BufferedEntry {
+    is_dir: bool,
+    included: bool,
+    size: Option<u64>,
+    stored_size: Option<u64>,
+    read_error: Option<String>,
+}
+
+/// Builds the whole tree in memory and writes it once the input ends, so
+/// entries can come in any order. A path seen more than once keeps the
+/// last entry.
+pub struct BufferedTreeWriter {
+    writer: TreeWriter,
+    // Paths are ordered component by component, which is a depth-first-search
+    // order the streaming writer needs.
+    entries: BTreeMap<PathBuf, BufferedEntry>,
+}
+
+impl BufferedTreeWriter {
+    pub fn new(writer: TreeWriter) -> Self {
+        BufferedTreeWriter {
+            writer,
+            entries: BTreeMap::new(),
+        }
+    }
+}
+
+impl Sink for BufferedTreeWriter {
+    fn entry(&mut self, entry: &Entry) -> Result<()> {
+        if self.writer.wants(entry) {
+            self.entries.insert(
+                entry.path.to_owned(),
+                BufferedEntry {
+                    is_dir: entry.is_dir,
+                    included: entry.included,
+                    size: entry.size,
+                    stored_size: entry.stored_size,
+                    read_error: entry.read_error.map(str::to_owned),
+                },
+            );
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        // Excluded directories are written without children, anything
+        // recorded under them is dropped.
+        let mut excluded_dir: Option<&Path> = None;
+        for (path, e) in &self.entries {
+            if excluded_dir.is_some_and(|dir| path.starts_with(dir)) {
+                continue;
+            }
+            excluded_dir = (!e.included && e.is_dir).then_some(path.as_path());
+            self.writer.entry(&Entry {
+                path,
+                is_dir: e.is_dir,
+                included: e.included,
+                pattern: None,
+                size: e.size,
+                stored_size: e.stored_size,
+                read_error: e.read_error.as_deref(),
+            })?;
+        }
+        Box::new(self.writer).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Backend writing the tree as lines, `name/` opens a directory and
+    /// `..` closes it.
+    #[derive(Clone, Default)]
+    struct Recorder(Rc<RefCell<Vec<String>>>);
+
+    impl Backend for Recorder {
+        fn open_dir(&mut self, info: &FileInfo) -> Result<()> {
+            let name = info.name.to_string_lossy();
+            self.0.borrow_mut().push(format!("{name}/"));
+            Ok(())
+        }
+        fn close_dir(&mut self) -> Result<()> {
+            self.0.borrow_mut().push("..".to_owned());
+            Ok(())
+        }
+        fn file(&mut self, info: &FileInfo) -> Result<()> {
+            self.0
+                .borrow_mut()
+                .push(info.name.to_string_lossy().into_owned());
+            Ok(())
+        }
+        fn finish(self: Box<Self>) -> Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Writes included entries, paths ending with `/` are directories, and
+    /// returns the written tree without the root.
+    fn tree(paths: &[&str]) -> Vec<String> {
+        write(paths).0
+    }
+
+    /// Number of entries warned about as out of order.
+    fn out_of_order(paths: &[&str]) -> u64 {
+        write(paths).1
+    }
+
+    fn write(paths: &[&str]) -> (Vec<String>, u64) {
+        let recorder = Recorder::default();
+        let backend = Box::new(recorder.clone());
+        let mut writer = Box::new(TreeWriter::new(backend, None, TreeOptions::default()).unwrap());
+        for path in paths {
+            writer
+                .entry(&Entry {
+                    path: Path::new(path.trim_end_matches('/')),
+                    is_dir: path.ends_with('/'),
+                    included: true,
+                    pattern: None,
+                    size: Some(1),
+                    stored_size: None,
+                    read_error: None,
+                })
+                .unwrap();
+        }
+        let out_of_order = writer.out_of_order;
+        writer.finish().unwrap();
+        let mut lines = recorder.0.take();
+        assert_eq!(lines.remove(0), "//");
+        assert_eq!(lines.pop().unwrap(), "..");
+        (lines, out_of_order)
+    }
+
+    #[test]
+    fn depth_first_order() {
+        let lines = tree(&["a/", "e/", "a/b/", "a/f", "a/b/g", "e/c/"]);
+        assert_eq!(
+            lines,
+            ["a/", "f", "b/", "g", "..", "..", "e/", "c/", "..", ".."]
+        );
+    }
+
+    #[test]
+    fn paths_are_normalized() {
+        assert_eq!(tree(&["/"]), [] as [&str; 0]);
+        assert_eq!(tree(&["./a"]), ["a"]);
+        assert_eq!(tree(&["/abs/file"]), ["abs/", "file", ".."]);
+        assert_eq!(tree(&["a/../b"]), ["b"]);
+        assert_eq!(tree(&["a/", "a/./b/"]), ["a/", "b/", "..", ".."]);
+        assert_eq!(tree(&["../x", "a/../../y"]), [] as [&str; 0]);
+    }
+
+    #[test]
+    fn directory_after_its_contents() {
+        let lines = tree(&["a/f.txt", "a/"]);
+        assert_eq!(lines, ["a/", "f.txt", ".."]);
+        let lines = tree(&["a/b/big", "a/b/", "a/f.txt"]);
+        assert_eq!(lines, ["a/", "b/", "big", "..", "f.txt", ".."]);
+    }
+
+    #[test]
+    fn sibling_order() {
+        let after = |a: &str, b: &str| sorts_after(Path::new(a), Path::new(b));
+        assert!(after("d/b", "d/a"));
+        assert!(!after("d/a", "d/b"));
+        assert!(!after("d/a", "d/a"));
+        // Either order of `a` and `a-b` is fine, Duplicacy puts `a-b` first
+        // as it compares `a-b/` with `a/`.
+        assert!(after("d/a", "d/a-b"));
+        assert!(after("d/a-b", "d/a"));
+    }
+
+    #[test]
+    fn out_of_order_input() {
+        // Duplicacy order, where `a-b` comes before `a`, and plain order.
+        assert_eq!(out_of_order(&["a-b/", "a/", "a-b/x", "a/x", "a/y", "c"]), 0);
+        assert_eq!(out_of_order(&["a/x", "a-b/x", "x", "x-y"]), 0);
+
+        assert_eq!(out_of_order(&["a/x", "b/y", "a/z"]), 1);
+        assert_eq!(out_of_order(&["a/f.txt", "a/"]), 1);
+        assert_eq!(out_of_order(&["a/b/big", "a/b/", "a/f.txt"]), 1);
+        assert_eq!(out_of_order(&["a/x", "a/x"]), 1);
+        assert_eq!(out_of_order(&["a/x", "a/y", "a/x"]), 1);
+    }
+}
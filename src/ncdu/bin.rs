@@ -0,0 +1,606 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! NCDU binary export format, supported by NCDU >=2.6, see
+//! https://dev.yorhel.nl/ncdu/binfmt
+use super::{Backend, Extended, FileInfo};
+use anyhow::Result;
+use clio::Output;
+use std::collections::HashMap;
+use std::ffi::OsString;
+use std::io::{BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+
+const SIGNATURE: &[u8] = b"\xbfncduEX1";
+
+/// Amount of uncompressed item data after which a data block is written.
+/// Item references can address at most 16 MiB of it.
+const BLOCK_SIZE: usize = 512 * 1024;
+const ZSTD_LEVEL: i32 = 3;
+
+// Item types.
+const TYPE_DIR: i64 = 0;
+const TYPE_REG: i64 = 1;
+const TYPE_NONREG: i64 = 2;
+const TYPE_LINK: i64 = 3;
+const TYPE_ERR: i64 = -1;
+const TYPE_PATTERN: i64 = -2;
+
+// Item map keys.
+const KEY_TYPE: u64 = 0;
+const KEY_NAME: u64 = 1;
+const KEY_PREV: u64 = 2;
+const KEY_ASIZE: u64 = 3;
+const KEY_DSIZE: u64 = 4;
+const KEY_DEV: u64 = 5;
+const KEY_RDERR: u64 = 6;
+const KEY_CUMASIZE: u64 = 7;
+const KEY_CUMDSIZE: u64 = 8;
+const KEY_SHRASIZE: u64 = 9;
+const KEY_SHRDSIZE: u64 = 10;
+const KEY_ITEMS: u64 = 11;
+const KEY_SUB: u64 = 12;
+const KEY_INO: u64 = 13;
+const KEY_NLINK: u64 = 14;
+const KEY_UID: u64 = 15;
+const KEY_GID: u64 = 16;
+const KEY_MODE: u64 = 17;
+const KEY_MTIME: u64 = 18;
+
+/// Minimal CBOR encoder for the subset used by the format.
+#[derive(Default)]
+struct Cbor(Vec<u8>);
+
+impl Cbor {
+    fn head(&mut self, major: u8, v: u64) {
+        let major = major << 5;
+        if v < 24 {
+            self.0.push(major | v as u8);
+        } else if v <= u8::MAX as u64 {
+            self.0.push(major | 24);
+            self.0.push(v as u8);
+        } else if v <= u16::MAX as u64 {
+            self.0.push(major | 25);
+            self.0.extend_from_slice(&(v as u16).to_be_bytes());
+        } else if v <= u32::MAX as u64 {
+            self.0.push(major | 26);
+            self.0.extend_from_slice(&(v as u32).to_be_bytes());
+        } else {
+            self.0.push(major | 27);
+            self.0.extend_from_slice(&v.to_be_bytes());
+        }
+    }
+
+    fn uint(&mut self, key: u64, v: u64) {
+        self.head(0, key);
+        self.head(0, v);
+    }
+
+    fn int(&mut self, key: u64, v: i64) {
+        self.head(0, key);
+        if v >= 0 {
+            self.head(0, v as u64);
+        } else {
+            self.head(1, (-1 - v) as u64);
+        }
+    }
+
+    fn bool(&mut self, key: u64, v: bool) {
+        self.head(0, key);
+        self.0.push(if v { 0xf5 } else { 0xf4 });
+    }
+
+    fn extended(&mut self, ext: Option<Extended>) {
+        if let Some(ext) = ext {
+            self.uint(KEY_UID, ext.uid.into());
+            self.uint(KEY_GID, ext.gid.into());
+            self.uint(KEY_MODE, ext.mode.into());
+            self.uint(KEY_MTIME, ext.mtime);
+        }
+    }
+
+    fn bytes(&mut self, key: u64, v: &[u8]) {
+        self.head(0, key);
+        self.head(2, v.len() as u64);
+        self.0.extend_from_slice(v);
+    }
+}
+
+/// Hard linked inode found under a directory.
+struct Link {
+    asize: u64,
+    dsize: u64,
+    nlink: u64,
+    /// Number of links to the inode under the directory.
+    count: u64,
+}
+
+/// Accumulated state of a directory which items are still being written.
+struct Dir {
+    name: OsString,
+    asize: u64,
+    dsize: u64,
+    dev: Option<u64>,
+    read_error: bool,
+    extended: Option<Extended>,
+    /// Reference to the last written child.
+    last: Option<u64>,
+    /// Cumulative sizes without hard linked inodes, those are counted only
+    /// once, from `links`.
+    cumasize: u64,
+    cumdsize: u64,
+    items: u64,
+    links: HashMap<(u64, u64), Link>,
+}
+
+impl Dir {
+    fn add_link(&mut self, key: (u64, u64), link: Link) {
+        self.links
+            .entry(key)
+            .and_modify(|l| l.count += link.count)
+            .or_insert(link);
+    }
+}
+
+pub struct BinBackend {
+    writer: BufWriter<Output>,
+    /// Position in the output.
+    offset: u64,
+    /// Uncompressed items of the current data block.
+    block: Vec<u8>,
+    /// Offset and length of every written data block.
+    index: Vec<(u64, u64)>,
+    dirs: Vec<Dir>,
+    root: Option<u64>,
+}
+
+impl BinBackend {
+    pub fn new(output: Output) -> Result<Self> {
+        let mut writer = BufWriter::new(output);
+        writer.write_all(SIGNATURE)?;
+        Ok(BinBackend {
+            writer,
+            offset: SIGNATURE.len() as u64,
+            block: Vec::new(),
+            index: Vec::new(),
+            dirs: Vec::new(),
+            root: None,
+        })
+    }
+
+    /// Writes encoded item and returns reference to it.
+    fn write_item(&mut self, item: Cbor) -> Result<u64> {
+        let itemref = ((self.index.len() as u64) << 24) | self.block.len() as u64;
+        // Items are written as CBOR maps of indefinite length.
+        self.block.push(0xbf);
+        self.block.extend_from_slice(&item.0);
+        self.block.push(0xff);
+        if self.block.len() >= BLOCK_SIZE {
+            self.flush_block()?;
+        }
+        Ok(itemref)
+    }
+
+    fn write_block(&mut self, block_type: u32, content: &[u8]) -> Result<()> {
+        let len = content.len() as u64 + 8;
+        let header = (block_type << 28) | len as u32;
+        self.writer.write_all(&header.to_be_bytes())?;
+        self.writer.write_all(content)?;
+        self.writer.write_all(&header.to_be_bytes())?;
+        self.offset += len;
+        Ok(())
+    }
+
+    fn flush_block(&mut self) -> Result<()> {
+        if self.block.is_empty() {
+            return Ok(());
+        }
+        let mut content = (self.index.len() as u32).to_be_bytes().to_vec();
+        content.extend(zstd::bulk::compress(&self.block, ZSTD_LEVEL)?);
+        self.index.push((self.offset, content.len() as u64 + 8));
+        self.write_block(0, &content)?;
+        self.block.clear();
+        Ok(())
+    }
+
+    /// Adds written item to the directory that is currently open.
+    fn add_to_parent(&mut self, itemref: u64, asize: u64, dsize: u64) {
+        match self.dirs.last_mut() {
+            Some(parent) => {
+                parent.last = Some(itemref);
+                parent.cumasize += asize;
+                parent.cumdsize += dsize;
+                parent.items += 1;
+            }
+            None => self.root = Some(itemref),
+        }
+    }
+}
+
+impl Backend for BinBackend {
+    fn open_dir(&mut self, info: &FileInfo) -> Result<()> {
+        self.dirs.push(Dir {
+            name: info.name.clone().into_owned(),
+            asize: info.asize,
+            dsize: info.dsize,
+            dev: info.dev,
+            read_error: info.read_error,
+            extended: info.extended,
+            last: None,
+            cumasize: info.asize,
+            cumdsize: info.dsize,
+            items: 0,
+            links: HashMap::new(),
+        });
+        Ok(())
+    }
+
+    fn close_dir(&mut self) -> Result<()> {
+        let dir = self.dirs.pop().unwrap();
+        let mut item = Cbor::default();
+        item.int(KEY_TYPE, TYPE_DIR);
+        item.bytes(KEY_NAME, dir.name.as_bytes());
+        if let Some(prev) = self.dirs.last().and_then(|p| p.last) {
+            item.uint(KEY_PREV, prev);
+        }
+        item.uint(KEY_ASIZE, dir.asize);
+        item.uint(KEY_DSIZE, dir.dsize);
+        // Device is stored only when it differs from the parent one.
+        if let Some(dev) = dir.dev
+            && self.dirs.last().is_none_or(|p| p.dev != dir.dev)
+        {
+            item.uint(KEY_DEV, dev);
+        }
+        item.extended(dir.extended);
+        if dir.read_error {
+            item.bool(KEY_RDERR, true);
+        }
+        let (mut cumasize, mut cumdsize) = (dir.cumasize, dir.cumdsize);
+        let (mut shrasize, mut shrdsize) = (0, 0);
+        for link in dir.links.values() {
+            cumasize += link.asize;
+            cumdsize += link.dsize;
+            // Some links are outside of this directory.
+            if link.count < link.nlink {
+                shrasize += link.asize;
+                shrdsize += link.dsize;
+            }
+        }
+        item.uint(KEY_CUMASIZE, cumasize);
+        item.uint(KEY_CUMDSIZE, cumdsize);
+        if shrasize > 0 || shrdsize > 0 {
+            item.uint(KEY_SHRASIZE, shrasize);
+            item.uint(KEY_SHRDSIZE, shrdsize);
+        }
+        item.uint(KEY_ITEMS, dir.items);
+        if let Some(sub) = dir.last {
+            item.uint(KEY_SUB, sub);
+        }
+        let itemref = self.write_item(item)?;
+        match self.dirs.last_mut() {
+            Some(parent) => {
+                parent.last = Some(itemref);
+                parent.cumasize += dir.cumasize;
+                parent.cumdsize += dir.cumdsize;
+                parent.items += dir.items + 1;
+                for (key, link) in dir.links {
+                    parent.add_link(key, link);
+                }
+            }
+            None => self.root = Some(itemref),
+        }
+        Ok(())
+    }
+
+    fn file(&mut self, info: &FileInfo) -> Result<()> {
+        let mut item = Cbor::default();
+        let (item_type, asize, dsize) = if info.excluded.is_some() {
+            (TYPE_PATTERN, 0, 0)
+        } else if info.read_error {
+            (TYPE_ERR, 0, 0)
+        } else if info.is_hard_link() {
+            (TYPE_LINK, info.asize, info.dsize)
+        } else if info.notreg {
+            (TYPE_NONREG, info.asize, info.dsize)
+        } else {
+            (TYPE_REG, info.asize, info.dsize)
+        };
+        item.int(KEY_TYPE, item_type);
+        item.bytes(KEY_NAME, info.name.as_bytes());
+        if let Some(prev) = self.dirs.last().and_then(|p| p.last) {
+            item.uint(KEY_PREV, prev);
+        }
+        if item_type != TYPE_PATTERN && item_type != TYPE_ERR {
+            item.uint(KEY_ASIZE, asize);
+            item.uint(KEY_DSIZE, dsize);
+        }
+        if item_type != TYPE_PATTERN && item_type != TYPE_ERR {
+            item.extended(info.extended);
+        }
+        if item_type == TYPE_LINK {
+            item.uint(KEY_INO, info.ino.unwrap());
+            item.uint(KEY_NLINK, info.nlink.unwrap());
+        }
+        let itemref = self.write_item(item)?;
+        if item_type == TYPE_LINK {
+            self.add_to_parent(itemref, 0, 0);
+            let parent = self.dirs.last_mut().unwrap();
+            let link = Link {
+                asize,
+                dsize,
+                nlink: info.nlink.unwrap(),
+                count: 1,
+            };
+            parent.add_link((info.dev.unwrap_or_default(), info.ino.unwrap()), link);
+        } else {
+            self.add_to_parent(itemref, asize, dsize);
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        self.flush_block()?;
+        let mut content = Vec::new();
+        for (offset, len) in &self.index {
+            content.extend_from_slice(&((offset << 24) | len).to_be_bytes());
+        }
+        content.extend_from_slice(&self.root.unwrap().to_be_bytes());
+        self.write_block(1, &content)?;
+        self.writer.into_inner()?.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ncdu::Backend;
+    use std::collections::BTreeMap;
+    use std::ffi::OsStr;
+    use tempfile::TempDir;
+
+    #[derive(Debug, PartialEq)]
+    enum Value {
+        Uint(u64),
+        Int(i64),
+        Bytes(Vec<u8>),
+        Bool(bool),
+    }
+
+    fn head(data: &[u8], pos: &mut usize) -> (u8, u64) {
+        let (major, info) = (data[*pos] >> 5, data[*pos] & 31);
+        *pos += 1;
+        let width = match info {
+            0..24 => return (major, info.into()),
+            24 => 1,
+            25 => 2,
+            26 => 4,
+            27 => 8,
+            _ => panic!("unexpected additional info {info}"),
+        };
+        let mut v = 0;
+        for b in &data[*pos..*pos + width] {
+            v = v << 8 | u64::from(*b);
+        }
+        *pos += width;
+        (major, v)
+    }
+
+    /// Decodes the item map at `pos` of an uncompressed data block.
+    fn item(data: &[u8], mut pos: usize) -> BTreeMap<u64, Value> {
+        assert_eq!(data[pos], 0xbf);
+        pos += 1;
+        let mut map = BTreeMap::new();
+        while data[pos] != 0xff {
+            let (major, key) = head(data, &mut pos);
+            assert_eq!(major, 0);
+            let value = match data[pos] {
+                0xf4 | 0xf5 => {
+                    pos += 1;
+                    Value::Bool(data[pos - 1] == 0xf5)
+                }
+                _ => match head(data, &mut pos) {
+                    (0, v) => Value::Uint(v),
+                    (1, v) => Value::Int(-1 - v as i64),
+                    (2, len) => {
+                        pos += len as usize;
+                        Value::Bytes(data[pos - len as usize..pos].to_vec())
+                    }
+                    (major, _) => panic!("unexpected major type {major}"),
+                },
+            };
+            map.insert(key, value);
+        }
+        map
+    }
+
+    /// Export with uncompressed data blocks and reference to the root.
+    struct Export {
+        blocks: Vec<Vec<u8>>,
+        root: u64,
+    }
+
+    impl Export {
+        fn read(data: &[u8]) -> Export {
+            assert_eq!(&data[..8], SIGNATURE);
+            let mut pos = 8;
+            let mut blocks = Vec::new();
+            let mut offsets = Vec::new();
+            loop {
+                let header = u32::from_be_bytes(data[pos..pos + 4].try_into().unwrap());
+                let len = (header & 0x0fff_ffff) as usize;
+                let content = &data[pos + 4..pos + len - 4];
+                assert_eq!(data[pos + len - 4..pos + len], header.to_be_bytes());
+                match header >> 28 {
+                    0 => {
+                        let number = u32::from_be_bytes(content[..4].try_into().unwrap());
+                        assert_eq!(number as usize, blocks.len());
+                        blocks.push(zstd::decode_all(&content[4..]).unwrap());
+                        offsets.push(((pos as u64) << 24) | len as u64);
+                    }
+                    1 => {
+                        let (index, root) = content.split_at(content.len() - 8);
+                        let index: Vec<_> = index
+                            .chunks(8)
+                            .map(|c| u64::from_be_bytes(c.try_into().unwrap()))
+                            .collect();
+                        assert_eq!(index, offsets);
+                        assert_eq!(pos + len, data.len());
+                        let root = u64::from_be_bytes(root.try_into().unwrap());
+                        return Export { blocks, root };
+                    }
+                    t => panic!("unexpected block type {t}"),
+                }
+                pos += len;
+            }
+        }
+
+        fn item(&self, itemref: u64) -> BTreeMap<u64, Value> {
+            let block = &self.blocks[(itemref >> 24) as usize];
+            item(block, (itemref & 0xff_ffff) as usize)
+        }
+
+        /// Children of the directory in the order they were written.
+        fn children(&self, dir: &BTreeMap<u64, Value>) -> Vec<BTreeMap<u64, Value>> {
+            let mut children = Vec::new();
+            let mut next = dir.get(&KEY_SUB);
+            while let Some(Value::Uint(itemref)) = next {
+                children.push(self.item(*itemref));
+                next = children.last().unwrap().get(&KEY_PREV);
+            }
+            children.reverse();
+            children
+        }
+    }
+
+    fn name(item: &BTreeMap<u64, Value>) -> &str {
+        match &item[&KEY_NAME] {
+            Value::Bytes(name) => std::str::from_utf8(name).unwrap(),
+            v => panic!("unexpected name {v:?}"),
+        }
+    }
+
+    fn info(name: &str, asize: u64) -> FileInfo<'_> {
+        FileInfo::recorded(OsStr::new(name), asize, None, false)
+    }
+
+    fn hard_link(name: &str) -> FileInfo<'_> {
+        FileInfo {
+            dev: Some(1),
+            ino: Some(7),
+            nlink: Some(3),
+            ..info(name, 1000)
+        }
+    }
+
+    fn write(f: impl FnOnce(&mut BinBackend)) -> Export {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("export.ncdu");
+        let mut backend = Box::new(BinBackend::new(Output::new(&path).unwrap()).unwrap());
+        f(&mut backend);
+        backend.finish().unwrap();
+        Export::read(&std::fs::read(path).unwrap())
+    }
+
+    fn encoded(f: impl FnOnce(&mut Cbor)) -> Vec<u8> {
+        let mut cbor = Cbor::default();
+        f(&mut cbor);
+        cbor.0
+    }
+
+    #[test]
+    fn head_widths() {
+        for (v, bytes) in [
+            (0, &[0x00][..]),
+            (23, &[0x17]),
+            (24, &[0x18, 24]),
+            (255, &[0x18, 0xff]),
+            (256, &[0x19, 0x01, 0x00]),
+            (65535, &[0x19, 0xff, 0xff]),
+            (65536, &[0x1a, 0x00, 0x01, 0x00, 0x00]),
+            (u32::MAX.into(), &[0x1a, 0xff, 0xff, 0xff, 0xff]),
+            (1 << 32, &[0x1b, 0, 0, 0, 1, 0, 0, 0, 0]),
+            (
+                u64::MAX,
+                &[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+            ),
+        ] {
+            assert_eq!(encoded(|c| c.head(0, v)), bytes, "{v}");
+        }
+        assert_eq!(encoded(|c| c.head(2, 300)), [0x59, 0x01, 0x2c]);
+    }
+
+    #[test]
+    fn negative_int() {
+        assert_eq!(encoded(|c| c.int(0, -1)), [0x00, 0x20]);
+        assert_eq!(encoded(|c| c.int(0, -2)), [0x00, 0x21]);
+        assert_eq!(encoded(|c| c.int(0, -25)), [0x00, 0x38, 24]);
+        assert_eq!(encoded(|c| c.int(0, 5)), [0x00, 0x05]);
+        assert_eq!(encoded(|c| c.int(0, i64::MIN))[1], 0x3b);
+    }
+
+    #[test]
+    fn tree_and_hard_links() {
+        let export = write(|b| {
+            b.open_dir(&info("/", 0)).unwrap();
+            b.file(&info("a", 100)).unwrap();
+            b.file(&FileInfo::unreadable(OsStr::new("e"))).unwrap();
+            b.file(&FileInfo::recorded(OsStr::new("x"), 5, None, true))
+                .unwrap();
+            b.open_dir(&info("d", 0)).unwrap();
+            b.file(&hard_link("l1")).unwrap();
+            b.file(&info("b", 10)).unwrap();
+            b.file(&hard_link("l2")).unwrap();
+            b.close_dir().unwrap();
+            b.file(&hard_link("l3")).unwrap();
+            b.close_dir().unwrap();
+        });
+        let root = export.item(export.root);
+        assert_eq!(name(&root), "/");
+        assert_eq!(root[&KEY_TYPE], Value::Uint(TYPE_DIR as u64));
+        // The inode is counted once, and all its links are in the root.
+        assert_eq!(root[&KEY_CUMASIZE], Value::Uint(100 + 10 + 1000));
+        assert_eq!(root.get(&KEY_SHRASIZE), None);
+        assert_eq!(root[&KEY_ITEMS], Value::Uint(8));
+
+        let children = export.children(&root);
+        let names: Vec<_> = children.iter().map(name).collect();
+        assert_eq!(names, ["a", "e", "x", "d", "l3"]);
+        assert_eq!(children[0][&KEY_TYPE], Value::Uint(TYPE_REG as u64));
+        assert_eq!(children[0][&KEY_ASIZE], Value::Uint(100));
+        assert_eq!(children[1][&KEY_TYPE], Value::Int(TYPE_ERR));
+        assert_eq!(children[1].get(&KEY_ASIZE), None);
+        assert_eq!(children[2][&KEY_TYPE], Value::Int(TYPE_PATTERN));
+        assert_eq!(children[4][&KEY_TYPE], Value::Uint(TYPE_LINK as u64));
+        assert_eq!(children[4][&KEY_INO], Value::Uint(7));
+        assert_eq!(children[4][&KEY_NLINK], Value::Uint(3));
+
+        // Two of the three links are in `d`, so the inode is shared.
+        let d = &children[3];
+        assert_eq!(d[&KEY_CUMASIZE], Value::Uint(1010));
+        assert_eq!(d[&KEY_SHRASIZE], Value::Uint(1000));
+        assert_eq!(d[&KEY_SHRDSIZE], Value::Uint(1000));
+        assert_eq!(d[&KEY_ITEMS], Value::Uint(3));
+        let children = export.children(d);
+        let names: Vec<_> = children.iter().map(name).collect();
+        assert_eq!(names, ["l1", "b", "l2"]);
+    }
+
+    #[test]
+    fn items_across_blocks() {
+        // Long names fill several data blocks.
+        let names: Vec<String> = (0..1500).map(|i| format!("{i:01000}")).collect();
+        let export = write(|b| {
+            b.open_dir(&info("/", 0)).unwrap();
+            for name in &names {
+                b.file(&info(name, 1)).unwrap();
+            }
+            b.close_dir().unwrap();
+        });
+        assert!(export.blocks.len() > 2);
+        assert_eq!(export.root >> 24, export.blocks.len() as u64 - 1);
+        let root = export.item(export.root);
+        assert_eq!(root[&KEY_CUMASIZE], Value::Uint(1500));
+        let children = export.children(&root);
+        assert_eq!(children.iter().map(name).collect::<Vec<_>>(), names);
+    }
+}
@@ -0,0 +1,464 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! NCDU JSON Export, see https://dev.yorhel.nl/ncdu/jsonfmt
+//!
+//! File names are byte strings that don't have to be valid UTF-8. Same as
+//! NCDU itself, they are written as they are, escaping only characters JSON
+//! requires to be escaped, which makes the document not strictly valid JSON
+//! but lets NCDU restore exact names. That's also why a generic JSON library
+//! isn't used here.
+use super::{Backend, FileInfo, Metadata};
+use crate::entry::Entry;
+use anyhow::{Result, bail};
+use clap::{crate_name, crate_version};
+use clio::Output;
+use std::ffi::OsStr;
+use std::io::{BufRead, BufWriter, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+/// Writes `s` as JSON string, the same way as NCDU does.
+fn write_str(w: &mut impl Write, s: &[u8]) -> Result<()> {
+    w.write_all(b"\"")?;
+    for &c in s {
+        match c {
+            b'"' => w.write_all(b"\\\"")?,
+            b'\\' => w.write_all(b"\\\\")?,
+            b'\n' => w.write_all(b"\\n")?,
+            b'\r' => w.write_all(b"\\r")?,
+            b'\t' => w.write_all(b"\\t")?,
+            0x08 => w.write_all(b"\\b")?,
+            0x0c => w.write_all(b"\\f")?,
+            0..0x20 | 0x7f => write!(w, "\\u{c:04x}")?,
+            _ => w.write_all(&[c])?,
+        }
+    }
+    w.write_all(b"\"")?;
+    Ok(())
+}
+
+pub struct JsonBackend {
+    writer: BufWriter<Output>,
+}
+
+impl JsonBackend {
+    pub fn new(output: Output, metadata: &Metadata) -> Result<Self> {
+        let mut writer = BufWriter::new(output);
+        // Format compatible with NCDU >=1.16
+        write!(writer, "[1,2,{{\"progname\":")?;
+        write_str(&mut writer, crate_name!().as_bytes())?;
+        write!(writer, ",\"progver\":")?;
+        write_str(&mut writer, crate_version!().as_bytes())?;
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        write!(writer, ",\"timestamp\":{timestamp}")?;
+        // Extra keys, NCDU ignores them.
+        if let Some(repository) = metadata.repository {
+            write!(writer, ",\"repository\":")?;
+            write_str(&mut writer, repository.as_os_str().as_bytes())?;
+        }
+        if let Some(backup_id) = metadata.backup_id {
+            write!(writer, ",\"backup_id\":")?;
+            write_str(&mut writer, backup_id.as_bytes())?;
+        }
+        write!(writer, "}}")?;
+        Ok(JsonBackend { writer })
+    }
+
+    /// Writes info block, `hlnkc` is set only for files as directories
+    /// always have multiple links.
+    fn write_info(&mut self, info: &FileInfo, hlnkc: bool) -> Result<()> {
+        let w = &mut self.writer;
+        write!(w, "{{\"name\":")?;
+        write_str(w, info.name.as_bytes())?;
+        write!(w, ",\"asize\":{},\"dsize\":{}", info.asize, info.dsize)?;
+        if let Some(dev) = info.dev {
+            write!(w, ",\"dev\":{dev}")?;
+        }
+        if let Some(ino) = info.ino {
+            write!(w, ",\"ino\":{ino}")?;
+        }
+        if let Some(nlink) = info.nlink {
+            write!(w, ",\"nlink\":{nlink}")?;
+        }
+        write!(w, ",\"notreg\":{}", info.notreg)?;
+        if let Some(ext) = info.extended {
+            write!(
+                w,
+                ",\"uid\":{},\"gid\":{},\"mode\":{},\"mtime\":{}",
+                ext.uid, ext.gid, ext.mode, ext.mtime
+            )?;
+        }
+        if hlnkc {
+            write!(w, ",\"hlnkc\":true")?;
+        }
+        if info.read_error {
+            write!(w, ",\"read_error\":true")?;
+        }
+        if let Some(excluded) = info.excluded {
+            write!(w, ",\"excluded\":")?;
+            write_str(w, excluded.as_bytes())?;
+        }
+        write!(w, "}}")?;
+        Ok(())
+    }
+}
+
+impl Backend for JsonBackend {
+    // Every entry follows the metadata or another entry, so it always
+    // starts with a comma.
+    fn open_dir(&mut self, info: &FileInfo) -> Result<()> {
+        write!(self.writer, ",[")?;
+        self.write_info(info, false)
+    }
+
+    fn close_dir(&mut self) -> Result<()> {
+        write!(self.writer, "]")?;
+        Ok(())
+    }
+
+    fn file(&mut self, info: &FileInfo) -> Result<()> {
+        write!(self.writer, ",")?;
+        self.write_info(info, info.is_hard_link())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        write!(self.writer, "]")?;
+        self.writer.into_inner()?.finish()?;
+        Ok(())
+    }
+}
+
+/// Info block of an entry in an export we read.
+#[derive(Default)]
+struct ReadInfo {
+    name: Vec<u8>,
+    asize: u64,
+    dsize: u64,
+    excluded: bool,
+}
+
+/// Minimal streaming JSON parser accepting names with arbitrary bytes, like
+/// the ones NCDU writes.
+struct Parser<R> {
+    reader: R,
+}
+
+impl<R: BufRead> Parser<R> {
+    /// Next byte after whitespace, without consuming it.
+    fn peek(&mut self) -> Result<u8> {
+        loop {
+            let Some(&c) = self.reader.fill_buf()?.first() else {
+                bail!("unexpected end of NCDU Export");
+            };
+            if !c.is_ascii_whitespace() {
+                return Ok(c);
+            }
+            self.reader.consume(1);
+        }
+    }
+
+    fn next(&mut self) -> Result<u8> {
+        let c = self.peek()?;
+        self.reader.consume(1);
+        Ok(c)
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        let c = self.next()?;
+        if c != expected {
+            bail!(
+                "invalid NCDU Export, expected '{}' but found '{}'",
+                expected as char,
+                c.escape_ascii()
+            );
+        }
+        Ok(())
+    }
+
+    /// Consumes `,` between elements and returns whether there is another
+    /// element before `close`, which is consumed too.
+    fn has_next(&mut self, first: &mut bool, close: u8) -> Result<bool> {
+        if self.peek()? == close {
+            self.reader.consume(1);
+            return Ok(false);
+        }
+        if !*first {
+            self.expect(b',')?;
+        }
+        *first = false;
+        Ok(true)
+    }
+
+    /// Reads a raw byte without skipping whitespace.
+    fn raw(&mut self) -> Result<u8> {
+        let Some(&c) = self.reader.fill_buf()?.first() else {
+            bail!("unexpected end of NCDU Export");
+        };
+        self.reader.consume(1);
+        Ok(c)
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let mut v = 0;
+        for _ in 0..4 {
+            let digit = (self.raw()? as char).to_digit(16);
+            let Some(digit) = digit else {
+                bail!("invalid \\u escape in NCDU Export");
+            };
+            v = v * 16 + digit;
+        }
+        Ok(v)
+    }
+
+    fn string(&mut self) -> Result<Vec<u8>> {
+        self.expect(b'"')?;
+        let mut s = Vec::new();
+        loop {
+            match self.raw()? {
+                b'"' => return Ok(s),
+                b'\\' => {
+                    let c = match self.raw()? {
+                        b'n' => '\n',
+                        b'r' => '\r',
+                        b't' => '\t',
+                        b'b' => '\x08',
+                        b'f' => '\x0c',
+                        b'u' => {
+                            let mut code = self.hex4()?;
+                            if (0xd800..0xdc00).contains(&code) {
+                                self.expect(b'\\')?;
+                                self.expect(b'u')?;
+                                let low = self.hex4()?;
+                                code =
+                                    0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00));
+                            }
+                            char::from_u32(code).unwrap_or(char::REPLACEMENT_CHARACTER)
+                        }
+                        c => c as char,
+                    };
+                    s.extend_from_slice(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                c => s.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<u64> {
+        let mut text = String::new();
+        self.peek()?;
+        while let Some(&c) = self.reader.fill_buf()?.first()
+            && (c.is_ascii_digit() || b"+-.eE".contains(&c))
+        {
+            text.push(c as char);
+            self.reader.consume(1);
+        }
+        // Sizes are integers, anything else is only skipped.
+        Ok(text.parse().unwrap_or(0))
+    }
+
+    fn skip_value(&mut self) -> Result<()> {
+        match self.peek()? {
+            b'"' => {
+                self.string()?;
+            }
+            open @ (b'[' | b'{') => {
+                self.reader.consume(1);
+                let close = if open == b'[' { b']' } else { b'}' };
+                let mut first = true;
+                while self.has_next(&mut first, close)? {
+                    if open == b'{' {
+                        self.string()?;
+                        self.expect(b':')?;
+                    }
+                    self.skip_value()?;
+                }
+            }
+            b'-' | b'0'..=b'9' => {
+                self.number()?;
+            }
+            _ => {
+                // true, false or null
+                while self.peek()?.is_ascii_alphabetic() {
+                    self.reader.consume(1);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn info(&mut self) -> Result<ReadInfo> {
+        let mut info = ReadInfo::default();
+        self.expect(b'{')?;
+        let mut first = true;
+        while self.has_next(&mut first, b'}')? {
+            let key = self.string()?;
+            self.expect(b':')?;
+            match key.as_slice() {
+                b"name" => info.name = self.string()?,
+                b"asize" => info.asize = self.number()?,
+                b"dsize" => info.dsize = self.number()?,
+                b"excluded" => {
+                    info.excluded = true;
+                    self.skip_value()?;
+                }
+                _ => self.skip_value()?,
+            }
+        }
+        Ok(info)
+    }
+}
+
+/// Reads NCDU JSON Export and calls `f` with every entry in it, in the
+/// order they are stored.
+pub fn read_export(reader: impl BufRead, mut f: impl FnMut(&Entry) -> Result<()>) -> Result<()> {
+    let mut parser = Parser { reader };
+    parser.expect(b'[')?;
+    let major = parser.number()?;
+    if major != 1 {
+        bail!("unsupported NCDU Export major version {major}");
+    }
+    // Minor version and metadata.
+    parser.expect(b',')?;
+    parser.skip_value()?;
+    parser.expect(b',')?;
+    parser.skip_value()?;
+
+    // Paths are relative to the root, so its info block is skipped.
+    parser.expect(b',')?;
+    parser.expect(b'[')?;
+    parser.skip_value()?;
+    read_dir(&mut parser, &mut PathBuf::new(), &mut f)?;
+
+    let mut first = false;
+    while parser.has_next(&mut first, b']')? {
+        parser.skip_value()?;
+    }
+    Ok(())
+}
+
+/// Reads entries of a directory which info block was already read, up to
+/// and including the closing `]`.
+fn read_dir(
+    parser: &mut Parser<impl BufRead>,
+    path: &mut PathBuf,
+    f: &mut impl FnMut(&Entry) -> Result<()>,
+) -> Result<()> {
+    let mut first = false;
+    while parser.has_next(&mut first, b']')? {
+        let is_dir = parser.peek()? == b'[';
+        if is_dir {
+            parser.expect(b'[')?;
+        }
+        let info = parser.info()?;
+        path.push(OsStr::from_bytes(&info.name));
+        f(&Entry {
+            path,
+            is_dir,
+            included: !info.excluded,
+            pattern: None,
+            size: Some(info.asize),
+            stored_size: Some(info.dsize),
+            read_error: None,
+        })?;
+        if is_dir {
+            read_dir(parser, path, f)?;
+        }
+        path.pop();
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::Path;
+    use tempfile::TempDir;
+
+    /// Names of entries with their size and whether they are directories.
+    fn read(export: &[u8]) -> Vec<(Vec<u8>, bool, u64)> {
+        let mut entries = Vec::new();
+        read_export(export, |e| {
+            let path = e.path.as_os_str().as_bytes().to_vec();
+            entries.push((path, e.is_dir, e.size.unwrap()));
+            Ok(())
+        })
+        .unwrap();
+        entries
+    }
+
+    #[test]
+    fn escaping() {
+        let mut out = Vec::new();
+        write_str(&mut out, b"a\"b\\c\n\r\t\x08\x0c\x01\x1f\x7f \xe9/\xc5\xbc").unwrap();
+        assert_eq!(
+            out,
+            b"\"a\\\"b\\\\c\\n\\r\\t\\b\\f\\u0001\\u001f\\u007f \xe9/\xc5\xbc\""
+        );
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let names: [&[u8]; 7] = [
+            b"quote\"d",
+            b"back\\slash",
+            b"\x01\x02\x1f\n\r\t\x08\x0c",
+            b"del\x7f",
+            b"caf\xe9",
+            b"\xff\xfe",
+            "za\u{17c}\u{f3}\u{142}\u{107}".as_bytes(),
+        ];
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("export.json");
+        let metadata = Metadata {
+            repository: Some(Path::new("/repo\"\\")),
+            backup_id: Some("id"),
+        };
+        let mut backend =
+            Box::new(JsonBackend::new(Output::new(&path).unwrap(), &metadata).unwrap());
+        let info = |name: &'static [u8], size| {
+            FileInfo::recorded(OsStr::from_bytes(name), size, None, false)
+        };
+        backend.open_dir(&info(b"/", 0)).unwrap();
+        for (i, name) in names.iter().enumerate() {
+            backend.file(&info(name, i as u64)).unwrap();
+        }
+        backend.open_dir(&info(b"d\"\x7f\xe9", 0)).unwrap();
+        backend.file(&info(b"in\\dir", 100)).unwrap();
+        backend.close_dir().unwrap();
+        backend.close_dir().unwrap();
+        backend.finish().unwrap();
+
+        let mut expected: Vec<_> = (names.iter().enumerate())
+            .map(|(i, name)| (name.to_vec(), false, i as u64))
+            .collect();
+        expected.push((b"d\"\x7f\xe9".to_vec(), true, 0));
+        expected.push((b"d\"\x7f\xe9/in\\dir".to_vec(), false, 100));
+        assert_eq!(read(&std::fs::read(path).unwrap()), expected);
+    }
+
+    #[test]
+    fn standard_json() {
+        // As written by JSON libraries, with whitespace and other escapes.
+        let export = r#"[1, 2, {"progname": "x", "extra": [1, {"a": null}]},
+            [{"name": "/", "asize": 0},
+             {"name": "caf\u00e9 \ud83d\ude00 \/", "asize": 5, "dsize": 4096, "hlnkc": true},
+             {"name": "ex", "excluded": "pattern"}]]"#;
+        let mut entries = Vec::new();
+        read_export(export.as_bytes(), |e| {
+            entries.push((e.path.to_owned(), e.included, e.size, e.stored_size));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            entries,
+            [
+                ("caf\u{e9} \u{1f600} /".into(), true, Some(5), Some(4096)),
+                ("ex".into(), false, Some(0), Some(0)),
+            ]
+        );
+    }
+}
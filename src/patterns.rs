@@ -0,0 +1,169 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Report of how much data every filter pattern includes or excludes.
+use crate::entry::{Entry, Sink};
+use crate::filters::Filters;
+use anyhow::Result;
+use clio::Output;
+use std::collections::HashMap;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+struct Usage {
+    files: u64,
+    dirs: u64,
+    bytes: u64,
+}
+
+pub struct PatternReport {
+    output: Output,
+    filters: Filters,
+    /// Repository root on the filesystem, `None` when the filesystem must
+    /// not be accessed and sizes come only from entries.
+    root: Option<PathBuf>,
+    /// Keyed by the pattern and inclusion decision, the decision is needed
+    /// only for entries not matched by any pattern.
+    usage: HashMap<(Option<String>, bool), Usage>,
+    /// Paths that couldn't be read with the reason, they are not counted.
+    errors: Vec<(PathBuf, String)>,
+}
+
+impl PatternReport {
+    pub fn new(output: Output, filters: Filters, root: Option<&Path>) -> Self {
+        PatternReport {
+            output,
+            filters,
+            root: root.map(Path::to_owned),
+            usage: HashMap::new(),
+            errors: Vec::new(),
+        }
+    }
+}
+
+/// Sums sizes of all files in the directory tree, without following symlinks.
+/// Whatever can't be read is recorded in `errors` and skipped.
+fn dir_usage(dir: &Path, usage: &mut Usage, errors: &mut Vec<(PathBuf, String)>) {
+    let mut dirs = vec![dir.to_owned()];
+    while let Some(dir) = dirs.pop() {
+        let read_dir = match std::fs::read_dir(&dir) {
+            Ok(read_dir) => read_dir,
+            Err(err) => {
+                errors.push((dir, err.to_string()));
+                continue;
+            }
+        };
+        for entry in read_dir {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(err) => {
+                    errors.push((dir.clone(), err.to_string()));
+                    break;
+                }
+            };
+            match entry.metadata() {
+                Ok(meta) if meta.is_dir() => dirs.push(entry.path()),
+                Ok(meta) => {
+                    usage.files += 1;
+                    usage.bytes += meta.len();
+                }
+                Err(err) => errors.push((entry.path(), err.to_string())),
+            }
+        }
+    }
+}
+
+impl Sink for PatternReport {
+    fn entry(&mut self, entry: &Entry) -> Result<()> {
+        if let Some(err) = entry.read_error {
+            self.errors.push((entry.path.to_owned(), err.to_owned()));
+            return Ok(());
+        }
+        let usage = self
+            .usage
+            .entry((entry.pattern.map(str::to_owned), entry.included))
+            .or_default();
+        if !entry.is_dir {
+            usage.bytes += match (entry.size, &self.root) {
+                (Some(size), _) => size,
+                (None, Some(root)) => match std::fs::symlink_metadata(root.join(entry.path)) {
+                    Ok(meta) => meta.len(),
+                    // Vanished since it was enumerated.
+                    Err(err) => {
+                        self.errors.push((entry.path.to_owned(), err.to_string()));
+                        return Ok(());
+                    }
+                },
+                (None, None) => 0,
+            };
+            usage.files += 1;
+        } else {
+            usage.dirs += 1;
+            // Included directories are accounted for by their files, but
+            // excluded ones are never descended into by Duplicacy.
+            if !entry.included
+                && let Some(root) = &self.root
+            {
+                dir_usage(&root.join(entry.path), usage, &mut self.errors);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self: Box<Self>) -> Result<()> {
+        let mut rows = Vec::new();
+        for pattern in self.filters.patterns() {
+            let included = pattern.starts_with('+') || pattern.starts_with("i:");
+            let usage = self
+                .usage
+                .remove(&(Some(pattern.to_owned()), included))
+                .unwrap_or_default();
+            rows.push((pattern.to_owned(), included, usage));
+        }
+        // Patterns seen in the log but missing in the filters file, e.g. when
+        // the file was modified since the log was created.
+        let mut rest: Vec<_> = self.usage.drain().collect();
+        rest.sort_by(|a, b| a.0.cmp(&b.0));
+        for ((pattern, included), usage) in rest {
+            let pattern = pattern.unwrap_or_else(|| "(no matching pattern)".to_owned());
+            rows.push((pattern, included, usage));
+        }
+
+        let mut out = BufWriter::new(self.output);
+        writeln!(out, "pattern\tdecision\tfiles\tdirs\tbytes")?;
+        for (pattern, included, usage) in &rows {
+            let decision = if *included { "included" } else { "excluded" };
+            writeln!(
+                out,
+                "{pattern}\t{decision}\t{}\t{}\t{}",
+                usage.files, usage.dirs, usage.bytes
+            )?;
+        }
+
+        let unused: Vec<_> = rows
+            .iter()
+            .filter(|(_, _, u)| u.files == 0 && u.dirs == 0)
+            .map(|(p, _, _)| p)
+            .collect();
+        if !unused.is_empty() {
+            writeln!(out)?;
+            writeln!(out, "Patterns that matched nothing:")?;
+            for pattern in unused {
+                writeln!(out, "{pattern}")?;
+            }
+        }
+        out.into_inner()?.finish()?;
+        if !self.errors.is_empty() {
+            eprintln!("{} entries couldn't be read:", self.errors.len());
+            for (path, err) in &self.errors {
+                // Errors from excluded directories have the full path.
+                let path = match &self.root {
+                    Some(root) => path.strip_prefix(root).unwrap_or(path),
+                    None => path,
+                };
+                eprintln!("  {}: {err}", path.display());
+            }
+        }
+        Ok(())
+    }
+}
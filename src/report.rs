@@ -0,0 +1,137 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Plain text summary of the biggest directories and files.
+use crate::entry::{Entry, Sink};
+use crate::repository::locate;
+use crate::walk::followed_link;
+use anyhow::Result;
+use clio::Output;
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+/// Formats size with binary units, the same as NCDU shows them.
+pub fn human_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["KiB", "MiB", "GiB", "TiB", "PiB", "EiB"];
+    if bytes < 1024 {
+        return format!("{bytes} B");
+    }
+    let mut size = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    format!("{size:.1} {}", UNITS[unit])
+}
+
+pub struct Report {
+    output: Output,
+    /// Path prefixes and repository roots to stat files in, empty when the
+    /// filesystem must not be accessed.
+    roots: Vec<(PathBuf, PathBuf)>,
+    /// Depth of directories to list, 1 is directories in the root.
+    depth: usize,
+    top: usize,
+    files: u64,
+    bytes: u64,
+    unreadable: u64,
+    dirs: HashMap<PathBuf, u64>,
+    /// The largest files seen so far, the smallest of them on top.
+    largest: BinaryHeap<Reverse<(u64, PathBuf)>>,
+}
+
+impl Report {
+    pub fn new(output: Output, roots: Vec<(PathBuf, PathBuf)>, depth: usize, top: usize) -> Self {
+        Report {
+            output,
+            roots,
+            depth,
+            top,
+            files: 0,
+            bytes: 0,
+            unreadable: 0,
+            dirs: HashMap::new(),
+            largest: BinaryHeap::new(),
+        }
+    }
+}
+
+/// Size of the file from the entry, or from the filesystem if one of `roots`
+/// has it. Files that can't be located anywhere have no size.
+pub fn file_size(roots: &[(PathBuf, PathBuf)], entry: &Entry) -> std::io::Result<u64> {
+    if let Some(size) = entry.size {
+        return Ok(size);
+    }
+    let Some((root, relative)) = locate(roots, entry.path) else {
+        return Ok(0);
+    };
+    let path = root.join(relative);
+    let meta = match followed_link(root, relative) {
+        Some(_) => std::fs::metadata(path)?,
+        None => std::fs::symlink_metadata(path)?,
+    };
+    Ok(meta.len())
+}
+
+impl Sink for Report {
+    fn entry(&mut self, entry: &Entry) -> Result<()> {
+        if !entry.included || entry.is_dir {
+            return Ok(());
+        }
+        let Ok(size) = file_size(&self.roots, entry) else {
+            self.unreadable += 1;
+            return Ok(());
+        };
+        self.files += 1;
+        self.bytes += size;
+
+        // Files above the depth are not in any listed directory.
+        if entry.path.components().count() > self.depth {
+            let dir: PathBuf = entry.path.components().take(self.depth).collect();
+            *self.dirs.entry(dir).or_default() += size;
+        }
+
+        if self.top > 0 {
+            self.largest.push(Reverse((size, entry.path.to_owned())));
+            if self.largest.len() > self.top {
+                self.largest.pop();
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        let mut out = BufWriter::new(self.output);
+        writeln!(
+            out,
+            "Total: {} in {} files",
+            human_size(self.bytes),
+            self.files
+        )?;
+
+        let mut dirs: Vec<(&Path, u64)> =
+            self.dirs.iter().map(|(p, s)| (p.as_path(), *s)).collect();
+        dirs.sort_by_key(|&(path, size)| (Reverse(size), path));
+        writeln!(out, "\nLargest directories at depth {}:", self.depth)?;
+        for (path, size) in dirs.iter().take(self.top) {
+            writeln!(out, "{:>12}  {}", human_size(*size), path.display())?;
+        }
+
+        writeln!(out, "\nLargest files:")?;
+        for Reverse((size, path)) in self.largest.into_sorted_vec() {
+            writeln!(out, "{:>12}  {}", human_size(size), path.display())?;
+        }
+
+        if self.unreadable > 0 {
+            writeln!(
+                out,
+                "\n{} files couldn't be read and are not counted",
+                self.unreadable
+            )?;
+        }
+        out.into_inner()?.finish()?;
+        Ok(())
+    }
+}
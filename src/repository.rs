@@ -0,0 +1,132 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Locating the Duplicacy repository and its preferences.
+use anyhow::{Context, Result};
+use serde::Deserialize;
+use std::io::BufReader;
+use std::path::{Path, PathBuf};
+use struson::reader::{JsonReader, JsonStreamReader};
+
+/// Entry of the `preferences` file, there is one per configured storage.
+#[derive(Deserialize)]
+struct Preference {
+    id: String,
+    /// Set when the repository was initialized with `-repository`.
+    #[serde(default)]
+    repository: String,
+    /// Custom filters file.
+    #[serde(default)]
+    filters: String,
+}
+
+pub struct Repository {
+    /// Directory paths in logs and listings are relative to.
+    pub root: PathBuf,
+    /// Directory with `.duplicacy`, where Duplicacy commands are run.
+    pub top: Option<PathBuf>,
+    /// Directory with `preferences` and `filters`, `None` if no repository
+    /// was found and the current or given directory is used as the root.
+    pub pref_dir: Option<PathBuf>,
+    pub backup_id: Option<String>,
+    filters: Option<PathBuf>,
+}
+
+impl Repository {
+    /// Finds the repository at `path`, or in the current directory or any of
+    /// its ancestors, by looking for `.duplicacy`. It's either the directory
+    /// with preferences or, with `-pref-dir`, a file with a path to it.
+    pub fn find(path: Option<&Path>) -> Result<Repository> {
+        let cwd = std::env::current_dir()?;
+        let top = match path {
+            Some(path) => Some(cwd.join(path)).filter(|p| p.join(".duplicacy").exists()),
+            None => cwd
+                .ancestors()
+                .find(|p| p.join(".duplicacy").exists())
+                .map(Path::to_owned),
+        };
+        let Some(top) = top else {
+            return Ok(Repository::unknown(cwd.join(path.unwrap_or(&cwd))));
+        };
+
+        let dot_duplicacy = top.join(".duplicacy");
+        let pref_dir = if dot_duplicacy.is_file() {
+            let content = std::fs::read_to_string(&dot_duplicacy)
+                .with_context(|| format!("failed to read {}", dot_duplicacy.display()))?;
+            top.join(content.trim())
+        } else {
+            dot_duplicacy
+        };
+
+        // All storages share the repository, the first one is the default.
+        let preference = read_preferences(&pref_dir.join("preferences"))?
+            .into_iter()
+            .next();
+
+        let root = match &preference {
+            Some(p) if !p.repository.is_empty() => top.join(&p.repository),
+            _ => top.clone(),
+        };
+        Ok(Repository {
+            root,
+            top: Some(top),
+            backup_id: preference.as_ref().map(|p| p.id.clone()),
+            filters: preference
+                .filter(|p| !p.filters.is_empty())
+                .map(|p| pref_dir.join(p.filters)),
+            pref_dir: Some(pref_dir),
+        })
+    }
+
+    /// Directory used as the root when there is no repository, with nothing
+    /// known about it.
+    pub fn unknown(root: PathBuf) -> Repository {
+        Repository {
+            root,
+            top: None,
+            pref_dir: None,
+            backup_id: None,
+            filters: None,
+        }
+    }
+
+    /// Warns when the repository wasn't found, so paths are likely not
+    /// relative to the root.
+    pub fn warn_if_not_found(&self) {
+        if self.pref_dir.is_none() {
+            eprintln!(
+                "warning: no .duplicacy found, using {} as the repository root",
+                self.root.display()
+            );
+        }
+    }
+
+    /// Filters file used for backups of the repository.
+    pub fn filters_path(&self) -> PathBuf {
+        match (&self.filters, &self.pref_dir) {
+            (Some(filters), _) => filters.clone(),
+            (None, Some(pref_dir)) => pref_dir.join("filters"),
+            (None, None) => self.root.join(".duplicacy/filters"),
+        }
+    }
+}
+
+/// Reads `preferences` file, a missing file is the same as no preferences.
+fn read_preferences(path: &Path) -> Result<Vec<Preference>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let file =
+        std::fs::File::open(path).with_context(|| format!("failed to read {}", path.display()))?;
+    JsonStreamReader::new(BufReader::new(file))
+        .deserialize_next()
+        .with_context(|| format!("invalid preferences file {}", path.display()))
+}
+
+/// Finds which of `roots`, pairs of a path prefix and a repository root on
+/// the filesystem, `path` belongs to. Returns the root and the path relative
+/// to it.
+pub fn locate<'a>(roots: &'a [(PathBuf, PathBuf)], path: &'a Path) -> Option<(&'a Path, &'a Path)> {
+    roots
+        .iter()
+        .find_map(|(prefix, root)| Some((root.as_path(), path.strip_prefix(prefix).ok()?)))
+}
@@ -0,0 +1,92 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Running `duplicacy backup -enum-only` and reading its log directly.
+use crate::ExportArgs;
+use crate::log::{self, LineParser, LogFormat};
+use crate::repository::Repository;
+use anyhow::{Context, Result, bail};
+use std::io::{BufReader, Read};
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+#[derive(clap::Args, Debug)]
+pub struct ScanArgs {
+    /// Duplicacy executable, looked up in PATH by default
+    #[arg(long, default_value = "duplicacy")]
+    duplicacy: PathBuf,
+
+    /// Repository directory, by default found by looking for `.duplicacy` in
+    /// the current directory and its parents
+    #[arg(long)]
+    repository: Option<PathBuf>,
+
+    /// Name of the storage to pass to `duplicacy backup -storage`
+    #[arg(long)]
+    storage: Option<String>,
+
+    #[command(flatten)]
+    export: ExportArgs,
+
+    /// Extra options for `duplicacy backup`, e.g. filter related ones
+    #[arg(last = true)]
+    backup_args: Vec<String>,
+}
+
+pub fn run(args: ScanArgs) -> Result<()> {
+    let repository = Repository::find(args.repository.as_deref())?;
+    let Some(top) = &repository.top else {
+        bail!("no Duplicacy repository found, run in one or pass --repository");
+    };
+
+    let mut command = Command::new(&args.duplicacy);
+    // `-log` and `-debug` are global options, they must come before the
+    // command, `-enum-only` is an option of the backup command.
+    command.args(["-log", "-debug", "backup", "-enum-only"]);
+    if let Some(storage) = &args.storage {
+        command.args(["-storage", storage]);
+    }
+    command
+        .args(&args.backup_args)
+        .current_dir(top)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped());
+    let mut child = command
+        .spawn()
+        .with_context(|| format!("failed to run {}", args.duplicacy.display()))?;
+
+    // Stderr is collected on another thread, so the child never blocks on
+    // it while we read stdout.
+    let mut stderr = child.stderr.take().unwrap();
+    let stderr_thread = std::thread::spawn(move || {
+        let mut content = Vec::new();
+        let _ = stderr.read_to_end(&mut content);
+        String::from_utf8_lossy(&content).into_owned()
+    });
+
+    let mut sink = args
+        .export
+        .sink(std::slice::from_ref(&repository), &[], true)?;
+    let stdout = BufReader::new(child.stdout.take().unwrap());
+    let read = log::read_log(stdout, &LineParser::new(LogFormat::Cli), |e| sink.entry(e));
+    if read.is_err() {
+        let _ = child.kill();
+    }
+    let status = child.wait()?;
+    let stderr = stderr_thread.join().unwrap();
+    read?;
+    // The export is completed even when duplicacy failed, it has everything
+    // that was enumerated before the failure. Its failure, e.g. an exceeded
+    // budget, matters less than the one of duplicacy.
+    let finished = sink.finish();
+    if !status.success() {
+        bail!(
+            "{} failed with {status}: {}",
+            args.duplicacy.display(),
+            stderr.trim()
+        );
+    }
+    if !stderr.trim().is_empty() {
+        eprintln!("{}", stderr.trim_end());
+    }
+    finished
+}
@@ -0,0 +1,527 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Reading of snapshots directly from a local Duplicacy storage.
+use crate::entry::Entry;
+use crate::log::split_dir;
+use anyhow::{Context, Result, bail};
+use base64::prelude::*;
+use clap::ValueEnum;
+use config::Config;
+use rsa::RsaPrivateKey;
+use serde::Deserialize;
+use std::collections::{HashMap, HashSet};
+use std::io::{BufRead, Read};
+use std::path::{Path, PathBuf};
+use struson::reader::{JsonReader, JsonStreamReader};
+
+mod chunk;
+mod config;
+mod key;
+mod msgpack;
+
+/// Mode bit of directories, the same as Go's `os.ModeDir`.
+const MODE_DIR: u32 = 1 << 31;
+
+/// Snapshot file from `snapshots/<id>/<revision>`.
+#[derive(Deserialize)]
+struct Snapshot {
+    #[serde(default)]
+    version: u32,
+    /// Hex encoded hashes of chunks with the list of files.
+    files: Vec<String>,
+    /// Hex encoded hashes of chunks with the list of file content chunks.
+    #[serde(default)]
+    chunks: Vec<String>,
+    /// Hex encoded hashes of chunks with the lengths of file content chunks.
+    #[serde(default)]
+    lengths: Vec<String>,
+}
+
+/// File entry from the snapshot file list in the original JSON format.
+#[derive(Deserialize)]
+struct JsonFileEntry {
+    /// Path, if it is valid UTF-8.
+    path: Option<String>,
+    /// Base64 encoded path, used instead of `path` if it isn't valid UTF-8.
+    name: Option<String>,
+    size: i64,
+    mode: u32,
+    /// Written only for regular files.
+    #[serde(default)]
+    content: String,
+}
+
+struct FileEntry {
+    path: Vec<u8>,
+    size: i64,
+    mode: u32,
+    /// Start chunk, start offset, end chunk and end offset of the file
+    /// content, the chunks are indexes into the snapshot chunk list.
+    content: [u64; 4],
+}
+
+/// How the stored size of a chunk used by multiple files is attributed to them.
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum SharedChunks {
+    /// Split proportionally to how many bytes of the chunk every file uses
+    Split,
+    /// Attribute whole chunk to the first file that uses it
+    First,
+}
+
+/// Layout of the file content chunks of a snapshot.
+struct Chunks {
+    hashes: Vec<Vec<u8>>,
+    /// Uncompressed length of every chunk.
+    lengths: Vec<u64>,
+    /// Size of every chunk file in the storage.
+    stored: Vec<u64>,
+}
+
+impl Chunks {
+    /// Returns chunk indexes and number of their bytes used by the file.
+    fn usage(&self, file: &FileEntry) -> Vec<(usize, u64)> {
+        let [start, start_offset, end, end_offset] = file.content;
+        let (start, end) = (start as usize, end as usize);
+        if file.mode & MODE_DIR != 0 || file.size <= 0 || end >= self.lengths.len() {
+            return Vec::new();
+        }
+        (start..=end)
+            .map(|i| {
+                let from = if i == start { start_offset } else { 0 };
+                let to = if i == end {
+                    end_offset
+                } else {
+                    self.lengths[i]
+                };
+                (i, to.saturating_sub(from))
+            })
+            .filter(|&(_, n)| n > 0)
+            .collect()
+    }
+}
+
+pub struct Storage {
+    dir: PathBuf,
+    config: Config,
+    rsa_key: Option<RsaPrivateKey>,
+}
+
+impl Storage {
+    /// Opens storage in `dir`, `password` is called only if the storage is
+    /// encrypted. The RSA private key is needed only for storages with RSA
+    /// encryption enabled, `rsa_passphrase` is called only if it's encrypted.
+    pub fn open(
+        dir: &Path,
+        password: impl FnOnce() -> Result<String>,
+        rsa_key_path: Option<&Path>,
+        rsa_passphrase: impl FnOnce() -> Result<String>,
+    ) -> Result<Storage> {
+        let config_path = dir.join("config");
+        let content = std::fs::read(&config_path)
+            .with_context(|| format!("failed to read {}", config_path.display()))?;
+        let rsa_key = match rsa_key_path {
+            Some(path) => Some(key::load(path, rsa_passphrase)?),
+            None => None,
+        };
+        Ok(Storage {
+            dir: dir.to_owned(),
+            config: Config::load(&content, password)?,
+            rsa_key,
+        })
+    }
+
+    /// Returns the only snapshot id in the storage.
+    pub fn only_snapshot_id(&self) -> Result<String> {
+        let mut ids = Vec::new();
+        for entry in std::fs::read_dir(self.dir.join("snapshots"))? {
+            ids.push(entry?.file_name().to_string_lossy().into_owned());
+        }
+        match ids.len() {
+            1 => Ok(ids.pop().unwrap()),
+            0 => bail!("there are no snapshots in the storage"),
+            _ => bail!(
+                "storage has multiple snapshot ids, pick one of: {}",
+                ids.join(", ")
+            ),
+        }
+    }
+
+    pub fn latest_revision(&self, id: &str) -> Result<u32> {
+        let dir = self.dir.join("snapshots").join(id);
+        let mut latest = None;
+        for entry in
+            std::fs::read_dir(&dir).with_context(|| format!("failed to list {}", dir.display()))?
+        {
+            if let Ok(revision) = entry?.file_name().to_string_lossy().parse::<u32>() {
+                latest = latest.max(Some(revision));
+            }
+        }
+        latest.with_context(|| format!("there are no revisions of snapshot {id}"))
+    }
+
+    fn chunk_path(&self, id: &str) -> Result<PathBuf> {
+        // Depending on the storage age and configuration, chunks can be nested
+        // in directories named after the id prefix. Chunks being pruned are
+        // renamed to fossils.
+        let chunks = self.dir.join("chunks");
+        let candidates = [
+            chunks.join(&id[..2]).join(&id[2..]),
+            chunks.join(id),
+            chunks.join(&id[..2]).join(&id[2..4]).join(&id[4..]),
+        ];
+        for candidate in candidates {
+            if candidate.exists() {
+                return Ok(candidate);
+            }
+            let fossil = candidate.with_extension("fsl");
+            if fossil.exists() {
+                return Ok(fossil);
+            }
+        }
+        bail!("chunk {id} not found in the storage");
+    }
+
+    fn read_chunk(&self, hash: &[u8]) -> Result<Vec<u8>> {
+        let id = self.config.chunk_id(hash);
+        let data = std::fs::read(self.chunk_path(&id)?)?;
+        let key = self.config.derive_key(&self.config.chunk_key, hash);
+        chunk::decode(&data, &key, self.rsa_key.as_ref())
+            .with_context(|| format!("failed to decode chunk {id}"))
+    }
+
+    fn load_snapshot(&self, id: &str, revision: u32) -> Result<Snapshot> {
+        let name = format!("snapshots/{id}/{revision}");
+        let path = self.dir.join(&name);
+        let data = std::fs::read(&path)
+            .with_context(|| format!("failed to read snapshot file {}", path.display()))?;
+        let key = self
+            .config
+            .derive_key(&self.config.file_key, name.as_bytes());
+        let content = chunk::decode(&data, &key, self.rsa_key.as_ref())
+            .context("failed to decode snapshot file")?;
+        JsonStreamReader::new(content.as_slice())
+            .deserialize_next()
+            .context("failed to parse snapshot file")
+    }
+
+    fn sequence(&self, hashes: &[String]) -> Result<SequenceReader<'_>> {
+        Ok(SequenceReader {
+            storage: self,
+            hashes: hashes.iter().map(hex::decode).collect::<Result<_, _>>()?,
+            next: 0,
+            chunk: Vec::new(),
+            pos: 0,
+        })
+    }
+
+    fn load_chunks(&self, snapshot: &Snapshot) -> Result<Chunks> {
+        let hex_hashes: Vec<String> = JsonStreamReader::new(self.sequence(&snapshot.chunks)?)
+            .deserialize_next()
+            .context("failed to parse snapshot chunk list")?;
+        let lengths: Vec<u64> = JsonStreamReader::new(self.sequence(&snapshot.lengths)?)
+            .deserialize_next()
+            .context("failed to parse snapshot chunk lengths")?;
+        if hex_hashes.len() != lengths.len() {
+            bail!("snapshot chunk list and chunk lengths don't match");
+        }
+        let hashes: Vec<Vec<u8>> = hex_hashes
+            .iter()
+            .map(hex::decode)
+            .collect::<Result<_, _>>()?;
+
+        // The same chunk can be listed multiple times.
+        let mut sizes = HashMap::new();
+        let mut stored = Vec::with_capacity(hashes.len());
+        for hash in &hashes {
+            let size = match sizes.get(hash) {
+                Some(&size) => size,
+                None => {
+                    let path = self.chunk_path(&self.config.chunk_id(hash))?;
+                    let size = std::fs::metadata(path)?.len();
+                    sizes.insert(hash, size);
+                    size
+                }
+            };
+            stored.push(size);
+        }
+        Ok(Chunks {
+            hashes,
+            lengths,
+            stored,
+        })
+    }
+
+    /// Calls `f` with every file and directory from the snapshot file list.
+    fn for_each_file(
+        &self,
+        snapshot: &Snapshot,
+        mut f: impl FnMut(&FileEntry) -> Result<()>,
+    ) -> Result<()> {
+        let mut reader = self.sequence(&snapshot.files)?;
+        match snapshot.version {
+            0 => {
+                let mut json_reader = JsonStreamReader::new(&mut reader);
+                json_reader.begin_array()?;
+                while json_reader.has_next()? {
+                    let entry: JsonFileEntry = json_reader.deserialize_next()?;
+                    let path = match (entry.path, &entry.name) {
+                        (Some(path), _) => path.into_bytes(),
+                        (None, Some(name)) => BASE64_STANDARD
+                            .decode(name)
+                            .context("invalid base64 file name in snapshot")?,
+                        (None, None) => bail!("file without path in snapshot"),
+                    };
+                    let mut content = [0; 4];
+                    if !entry.content.is_empty() {
+                        for (i, v) in entry.content.split(':').take(4).enumerate() {
+                            content[i] = v.parse()?;
+                        }
+                    }
+                    f(&FileEntry {
+                        path,
+                        size: entry.size,
+                        mode: entry.mode,
+                        content,
+                    })?;
+                }
+            }
+            1 => {
+                let mut decoder = msgpack::Decoder::new(&mut reader);
+                // Chunk indexes are stored as a difference from the previous
+                // file end chunk and start chunk.
+                let mut last_end_chunk = 0;
+                while !decoder.at_end()? {
+                    let path = decoder.bytes()?;
+                    let size = decoder.int()?;
+                    let _time = decoder.int()?;
+                    let mode = decoder.int()? as u32;
+                    let _link = decoder.bytes()?;
+                    let _hash = decoder.bytes()?;
+                    let start_chunk = last_end_chunk + decoder.int()? as u64;
+                    let start_offset = decoder.int()? as u64;
+                    let end_chunk = start_chunk + decoder.int()? as u64;
+                    let end_offset = decoder.int()? as u64;
+                    last_end_chunk = end_chunk;
+                    let _uid = decoder.int()?;
+                    let _gid = decoder.int()?;
+                    for _ in 0..decoder.int()? {
+                        let _name = decoder.bytes()?;
+                        let _value = decoder.bytes()?;
+                    }
+                    f(&FileEntry {
+                        path,
+                        size,
+                        mode,
+                        content: [start_chunk, start_offset, end_chunk, end_offset],
+                    })?;
+                }
+            }
+            v => bail!("unsupported snapshot format version {v}"),
+        }
+        Ok(())
+    }
+
+    /// Calls `f` with every file and directory stored in the snapshot
+    /// revision, in the order they are stored. Their stored size is the size
+    /// of chunks in the storage they use.
+    pub fn read_snapshot(
+        &self,
+        id: &str,
+        revision: u32,
+        shared: SharedChunks,
+        mut f: impl FnMut(&Entry) -> Result<()>,
+    ) -> Result<()> {
+        let snapshot = self.load_snapshot(id, revision)?;
+        let chunks = self.load_chunks(&snapshot)?;
+
+        // Splitting chunks requires knowing how many bytes of every chunk are
+        // used in total, so the file list is read twice.
+        let mut used: HashMap<&[u8], u64> = HashMap::new();
+        if let SharedChunks::Split = shared {
+            self.for_each_file(&snapshot, |file| {
+                for (i, n) in chunks.usage(file) {
+                    *used.entry(&chunks.hashes[i]).or_default() += n;
+                }
+                Ok(())
+            })?;
+        }
+
+        let mut seen = HashSet::new();
+        self.for_each_file(&snapshot, |file| {
+            let mut stored_size = 0;
+            for (i, n) in chunks.usage(file) {
+                let hash = chunks.hashes[i].as_slice();
+                stored_size += match shared {
+                    SharedChunks::Split => {
+                        (chunks.stored[i] as u128 * n as u128 / used[hash] as u128) as u64
+                    }
+                    SharedChunks::First if seen.insert(hash) => chunks.stored[i],
+                    SharedChunks::First => 0,
+                };
+            }
+            f(&Entry {
+                path: split_dir(&file.path).0,
+                is_dir: file.mode & MODE_DIR != 0,
+                included: true,
+                pattern: None,
+                size: Some(file.size as u64),
+                stored_size: Some(stored_size),
+                read_error: None,
+            })
+        })
+    }
+}
+
+/// Content of a sequence of chunks, loaded one chunk at a time.
+struct SequenceReader<'a> {
+    storage: &'a Storage,
+    hashes: Vec<Vec<u8>>,
+    next: usize,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for SequenceReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for SequenceReader<'_> {
+    fn fill_buf(&mut self) -> std::io::Result<&[u8]> {
+        while self.pos == self.chunk.len() && self.next < self.hashes.len() {
+            self.chunk = self
+                .storage
+                .read_chunk(&self.hashes[self.next])
+                .map_err(std::io::Error::other)?;
+            self.next += 1;
+            self.pos = 0;
+        }
+        Ok(&self.chunk[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Files in the fixture storages, see `tests/fixtures/mkstorage.py`.
+    const FILES: &[(&[u8], bool, u64)] = &[
+        (b"a", true, 0),
+        (b"a/b", true, 0),
+        (b"a/b/big.bin", false, 6000),
+        (b"a/f.txt", false, 3000),
+        (b"c", true, 0),
+        (b"c/caf\xe9", false, 500),
+        (b"c/empty", false, 0),
+        (b"top", false, 500),
+    ];
+
+    fn open(name: &str, password: Option<&str>) -> Result<Storage> {
+        let dir = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/storage");
+        let password = password.map(str::to_owned);
+        let no_passphrase = || unreachable!();
+        Storage::open(
+            &dir.join(name),
+            || password.context("no password"),
+            None,
+            no_passphrase,
+        )
+    }
+
+    /// Path, whether it's a directory, size and stored size of every entry.
+    fn read(storage: &Storage, shared: SharedChunks) -> Vec<(Vec<u8>, bool, u64, u64)> {
+        use std::os::unix::ffi::OsStrExt;
+        let id = storage.only_snapshot_id().unwrap();
+        let revision = storage.latest_revision(&id).unwrap();
+        assert_eq!((id.as_str(), revision), ("test", 3));
+        let mut entries = Vec::new();
+        storage
+            .read_snapshot(&id, revision, shared, |e| {
+                entries.push((
+                    e.path.as_os_str().as_bytes().to_vec(),
+                    e.is_dir,
+                    e.size.unwrap(),
+                    e.stored_size.unwrap(),
+                ));
+                Ok(())
+            })
+            .unwrap();
+        entries
+    }
+
+    /// Checks the entries and that stored sizes add up to the size of the
+    /// content chunks, whichever way they are attributed.
+    fn check(storage: &Storage) {
+        let snapshot = storage.load_snapshot("test", 3).unwrap();
+        let chunks = storage.load_chunks(&snapshot).unwrap();
+        let stored: u64 = chunks.stored.iter().sum();
+        for shared in [SharedChunks::Split, SharedChunks::First] {
+            let entries = read(storage, shared);
+            let files: Vec<_> = entries
+                .iter()
+                .map(|(path, is_dir, size, _)| (path.as_slice(), *is_dir, *size))
+                .collect();
+            assert_eq!(files, FILES);
+            // With `First`, files in chunks already counted have no stored size.
+            if let SharedChunks::Split = shared {
+                for (_, _, size, stored_size) in &entries {
+                    assert_eq!(*size == 0, *stored_size == 0);
+                }
+            }
+            let total: u64 = entries.iter().map(|e| e.3).sum();
+            // Splitting rounds down.
+            assert!(total <= stored && total + entries.len() as u64 >= stored);
+        }
+    }
+
+    #[test]
+    fn v0_snapshot() {
+        check(&open("v0-zlib", None).unwrap());
+    }
+
+    #[test]
+    fn v1_snapshot() {
+        check(&open("v1-lz4", None).unwrap());
+    }
+
+    #[test]
+    fn encrypted_storage() {
+        check(&open("v1-encrypted", Some("secret")).unwrap());
+        assert!(open("v1-encrypted", Some("wrong")).is_err());
+    }
+
+    #[test]
+    fn chunks_in_all_layouts() {
+        let storage = open("v0-zlib", None).unwrap();
+        let snapshot = storage.load_snapshot("test", 3).unwrap();
+        let hashes = snapshot.files.iter().chain(&snapshot.chunks);
+        let paths: Vec<PathBuf> = hashes
+            .map(|hash| {
+                let id = storage.config.chunk_id(&hex::decode(hash).unwrap());
+                storage.chunk_path(&id).unwrap()
+            })
+            .collect();
+        let chunks = storage.dir.join("chunks");
+        let depths: HashSet<usize> = paths
+            .iter()
+            .map(|p| p.strip_prefix(&chunks).unwrap().components().count())
+            .collect();
+        assert_eq!(depths, HashSet::from([1, 2, 3]));
+        assert!(
+            paths
+                .iter()
+                .any(|p| p.extension().is_some_and(|e| e == "fsl"))
+        );
+        assert!(storage.chunk_path(&"00".repeat(32)).is_err());
+    }
+}
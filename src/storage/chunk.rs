@@ -0,0 +1,131 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+use aes_gcm::aead::{Aead, KeyInit};
+use aes_gcm::{Aes256Gcm, Nonce};
+use anyhow::{Context, Result, bail};
+use flate2::read::ZlibDecoder;
+use rsa::{Oaep, RsaPrivateKey};
+use sha2::Sha256;
+use std::io::Read;
+
+/// Prefix of all encrypted files in the storage, followed by version byte.
+pub const ENCRYPTION_BANNER: &[u8] = b"duplicacy";
+
+/// Version byte of data encrypted with AES key which itself is encrypted
+/// with the storage RSA public key.
+const ENCRYPTION_VERSION_RSA: u8 = 2;
+
+const NONCE_SIZE: usize = 12;
+
+/// Decodes content of a chunk or snapshot file. Empty `key` means that the
+/// storage is not encrypted.
+pub fn decode(data: &[u8], key: &[u8], rsa_key: Option<&RsaPrivateKey>) -> Result<Vec<u8>> {
+    if key.is_empty() {
+        if data.starts_with(ENCRYPTION_BANNER) {
+            bail!("data is encrypted but storage is not");
+        }
+        return decompress(data);
+    }
+
+    let mut header = ENCRYPTION_BANNER.len() + 1;
+    if data.len() < header + NONCE_SIZE || !data.starts_with(ENCRYPTION_BANNER) {
+        bail!("data is not encrypted");
+    }
+    let mut key = key.to_vec();
+    match data[header - 1] {
+        0 => {}
+        ENCRYPTION_VERSION_RSA => {
+            let rsa_key = rsa_key.context("data is encrypted with RSA, private key is needed")?;
+            let len = u16::from_le_bytes([data[header], data[header + 1]]) as usize;
+            let encrypted_key = data
+                .get(header + 2..header + 2 + len)
+                .context("truncated encrypted data")?;
+            key = rsa_key
+                .decrypt(Oaep::new::<Sha256>(), encrypted_key)
+                .context("failed to decrypt data key with the RSA private key")?;
+            header += 2 + len;
+        }
+        v => bail!("unsupported encryption version {v}"),
+    }
+    if data.len() < header + NONCE_SIZE {
+        bail!("truncated encrypted data");
+    }
+
+    let cipher = Aes256Gcm::new_from_slice(&key).context("invalid encryption key")?;
+    let nonce = Nonce::from_slice(&data[header..header + NONCE_SIZE]);
+    let mut plain = cipher
+        .decrypt(nonce, &data[header + NONCE_SIZE..])
+        .map_err(|_| anyhow::anyhow!("failed to decrypt data, wrong key?"))?;
+
+    // Data is padded to a multiple of 256 bytes, PKCS7 style.
+    let padding = match plain.last() {
+        Some(0) => 256,
+        Some(&p) => p as usize,
+        None => bail!("decrypted data is empty"),
+    };
+    plain.truncate(plain.len().saturating_sub(padding));
+    decompress(&plain)
+}
+
+fn decompress(data: &[u8]) -> Result<Vec<u8>> {
+    if let Some(data) = data.strip_prefix(b"LZ4 ") {
+        // Block prefixed with the little endian uncompressed size.
+        return lz4_flex::block::decompress_size_prepended(data).context("corrupted LZ4 data");
+    }
+    if let Some(data) = data.strip_prefix(b"ZSTD") {
+        return zstd::stream::decode_all(data).context("corrupted ZSTD data");
+    }
+    let mut content = Vec::new();
+    ZlibDecoder::new(data)
+        .read_to_end(&mut content)
+        .context("corrupted zlib data")?;
+    Ok(content)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::write::ZlibEncoder;
+    use std::io::Write;
+
+    const DATA: &[u8] = b"duplicacy duplicacy duplicacy duplicacy";
+
+    #[test]
+    fn lz4() {
+        let mut data = b"LZ4 ".to_vec();
+        data.extend(lz4_flex::block::compress_prepend_size(DATA));
+        assert_eq!(decode(&data, b"", None).unwrap(), DATA);
+    }
+
+    #[test]
+    fn zstd() {
+        let mut data = b"ZSTD".to_vec();
+        data.extend(zstd::stream::encode_all(DATA, 3).unwrap());
+        assert_eq!(decode(&data, b"", None).unwrap(), DATA);
+    }
+
+    #[test]
+    fn zlib() {
+        let mut encoder = ZlibEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder.write_all(DATA).unwrap();
+        assert_eq!(decode(&encoder.finish().unwrap(), b"", None).unwrap(), DATA);
+    }
+
+    #[test]
+    fn truncated_rsa_encrypted_data() {
+        use rsa::pkcs1::DecodeRsaPrivateKey;
+        let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/fixtures/keys");
+        let pem = std::fs::read_to_string(dir.join("rsa.pem")).unwrap();
+        let key = RsaPrivateKey::from_pkcs1_pem(&pem).unwrap();
+        let data = std::fs::read(dir.join("truncated-chunk")).unwrap();
+        let err = decode(&data, &[0; 32], Some(&key)).unwrap_err();
+        assert_eq!(err.to_string(), "truncated encrypted data");
+    }
+
+    #[test]
+    fn encrypted_data_needs_key() {
+        let data = b"duplicacy\x00nonce and ciphertext";
+        assert!(decode(data, b"", None).is_err());
+        assert!(decode(b"not encrypted", &[0; 32], None).is_err());
+    }
+}
@@ -0,0 +1,182 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+use super::chunk::{self, ENCRYPTION_BANNER};
+use anyhow::{Context, Result, bail};
+use hmac::{Hmac, Mac};
+use serde::Deserialize;
+use sha2::Sha256;
+use struson::reader::{JsonReader, JsonStreamReader};
+
+/// Salt for the key derivation used in the original encrypted config format.
+const DEFAULT_KEY: &[u8] = b"duplicacy";
+const CONFIG_DEFAULT_ITERATIONS: u32 = 16384;
+/// Version byte of the config format with random salt and configurable
+/// number of key derivation iterations.
+const CONFIG_VERSION_SALTED: u8 = 1;
+const CONFIG_SALT_LENGTH: usize = 32;
+
+/// Compression level meaning LZ4 compression and BLAKE2b hashing, all other
+/// levels use HMAC-SHA256 for hashing.
+pub const DEFAULT_COMPRESSION_LEVEL: i32 = 100;
+
+#[derive(Deserialize)]
+#[serde(rename_all = "kebab-case")]
+struct RawConfig {
+    compression_level: i32,
+    id_key: String,
+    #[serde(default)]
+    chunk_key: String,
+    #[serde(default)]
+    file_key: String,
+    #[serde(default)]
+    data_shards: u32,
+}
+
+/// Storage configuration from the `config` file in the storage root.
+pub struct Config {
+    pub compression_level: i32,
+    pub id_key: Vec<u8>,
+    /// Key for chunks, empty when storage is not encrypted.
+    pub chunk_key: Vec<u8>,
+    /// Key for snapshot files, empty when storage is not encrypted.
+    pub file_key: Vec<u8>,
+}
+
+impl Config {
+    /// Parses content of the `config` file, `password` is called only when
+    /// the config is encrypted.
+    pub fn load(content: &[u8], password: impl FnOnce() -> Result<String>) -> Result<Config> {
+        if !content.starts_with(ENCRYPTION_BANNER) {
+            return Config::parse(content);
+        }
+        let header = ENCRYPTION_BANNER.len() + 1;
+        let (salt, iterations, encrypted) = match content.get(header - 1) {
+            Some(0) => (DEFAULT_KEY, CONFIG_DEFAULT_ITERATIONS, content.to_vec()),
+            Some(&CONFIG_VERSION_SALTED) if content.len() >= header + CONFIG_SALT_LENGTH + 4 => {
+                let salt = &content[header..header + CONFIG_SALT_LENGTH];
+                let rest = &content[header + CONFIG_SALT_LENGTH..];
+                let iterations = u32::from_le_bytes(rest[..4].try_into().unwrap());
+                // Salt and iterations removed, the rest is encrypted as any
+                // other file in the storage.
+                let mut encrypted = ENCRYPTION_BANNER.to_vec();
+                encrypted.push(0);
+                encrypted.extend_from_slice(&rest[4..]);
+                (salt, iterations, encrypted)
+            }
+            _ => bail!("unsupported encrypted config format"),
+        };
+        let key = pbkdf2::pbkdf2_hmac_array::<Sha256, 32>(password()?.as_bytes(), salt, iterations);
+        let content = chunk::decode(&encrypted, &key, None)
+            .context("failed to decrypt storage config, wrong password?")?;
+        Config::parse(&content)
+    }
+
+    fn parse(content: &[u8]) -> Result<Config> {
+        let raw: RawConfig = JsonStreamReader::new(content)
+            .deserialize_next()
+            .context("failed to parse storage config")?;
+        if raw.data_shards > 0 {
+            bail!("storages with erasure coding are not supported");
+        }
+        Ok(Config {
+            compression_level: raw.compression_level,
+            id_key: hex::decode(raw.id_key)?,
+            chunk_key: hex::decode(raw.chunk_key)?,
+            file_key: hex::decode(raw.file_key)?,
+        })
+    }
+
+    /// Keyed hash the same as Duplicacy uses for the chunk hashes and ids.
+    pub fn keyed_hash(&self, key: &[u8], data: &[u8]) -> Vec<u8> {
+        if self.compression_level == DEFAULT_COMPRESSION_LEVEL {
+            blake2b_simd::Params::new()
+                .hash_length(32)
+                .key(key)
+                .hash(data)
+                .as_bytes()
+                .to_vec()
+        } else {
+            let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+            mac.update(data);
+            mac.finalize().into_bytes().to_vec()
+        }
+    }
+
+    /// Key for decryption of data stored under `derivation_key`, e.g. the
+    /// chunk hash or the snapshot file path.
+    pub fn derive_key(&self, key: &[u8], derivation_key: &[u8]) -> Vec<u8> {
+        if key.is_empty() {
+            return Vec::new();
+        }
+        // Longer keys don't fit into BLAKE2b, Duplicacy keeps their ending.
+        let derivation_key = &derivation_key[derivation_key.len().saturating_sub(64)..];
+        self.keyed_hash(derivation_key, key)
+    }
+
+    /// Id of the chunk, which determines its file name, from its hash.
+    pub fn chunk_id(&self, hash: &[u8]) -> String {
+        hex::encode(self.keyed_hash(&self.id_key, hash))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config(compression_level: i32) -> Config {
+        Config {
+            compression_level,
+            id_key: DEFAULT_KEY.to_vec(),
+            chunk_key: Vec::new(),
+            file_key: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn keyed_hash_depends_on_compression_level() {
+        let hmac = config(6).keyed_hash(DEFAULT_KEY, b"abc");
+        assert_eq!(
+            hex::encode(hmac),
+            "6e37af71746c62922905603e43e45dd423ad307901cb1db4c81dba962d663fb8"
+        );
+        let blake2b = config(DEFAULT_COMPRESSION_LEVEL).keyed_hash(DEFAULT_KEY, b"abc");
+        assert_eq!(
+            hex::encode(blake2b),
+            "164340b81447949f00ac6381e79867d9ad9d98c32195af7b32d15c3437781313"
+        );
+    }
+
+    #[test]
+    fn chunk_id_is_keyed_hash_of_chunk_hash() {
+        assert_eq!(
+            config(6).chunk_id(b"abc"),
+            "6e37af71746c62922905603e43e45dd423ad307901cb1db4c81dba962d663fb8"
+        );
+    }
+
+    #[test]
+    fn derive_key_keeps_ending_of_long_derivation_key() {
+        let config = config(DEFAULT_COMPRESSION_LEVEL);
+        let derivation_key: Vec<u8> = (0..100).collect();
+        assert_eq!(
+            hex::encode(config.derive_key(b"key", &derivation_key)),
+            "aedf63afa25bcebc96ceffc300a2c588095c908a93e0c122c31c2f0300c73515"
+        );
+        assert!(config.derive_key(b"", &derivation_key).is_empty());
+    }
+
+    #[test]
+    fn plain_config() {
+        let content = br#"{"compression-level": 6, "id-key": "6964", "chunk-seed": "00"}"#;
+        let config = Config::load(content, || panic!("password asked")).unwrap();
+        assert_eq!(config.compression_level, 6);
+        assert_eq!(config.id_key, b"id");
+        assert!(config.chunk_key.is_empty() && config.file_key.is_empty());
+    }
+
+    #[test]
+    fn erasure_coding_is_rejected() {
+        let content = br#"{"compression-level": 6, "id-key": "", "data-shards": 5}"#;
+        assert!(Config::load(content, || panic!("password asked")).is_err());
+    }
+}
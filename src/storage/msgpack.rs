@@ -0,0 +1,100 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Minimal MessagePack decoder for the snapshot file lists.
+use anyhow::{Result, bail};
+use std::io::BufRead;
+
+pub struct Decoder<R: BufRead> {
+    reader: R,
+}
+
+impl<R: BufRead> Decoder<R> {
+    pub fn new(reader: R) -> Self {
+        Decoder { reader }
+    }
+
+    fn byte(&mut self) -> Result<u8> {
+        let mut b = [0; 1];
+        self.reader.read_exact(&mut b)?;
+        Ok(b[0])
+    }
+
+    fn be(&mut self, n: usize) -> Result<u64> {
+        let mut v = 0;
+        for _ in 0..n {
+            v = (v << 8) | self.byte()? as u64;
+        }
+        Ok(v)
+    }
+
+    pub fn at_end(&mut self) -> Result<bool> {
+        Ok(self.reader.fill_buf()?.is_empty())
+    }
+
+    pub fn int(&mut self) -> Result<i64> {
+        let b = self.byte()?;
+        Ok(match b {
+            0x00..=0x7f => b as i64,
+            0xe0..=0xff => b as i8 as i64,
+            0xcc => self.be(1)? as i64,
+            0xcd => self.be(2)? as i64,
+            0xce => self.be(4)? as i64,
+            0xcf => self.be(8)? as i64,
+            0xd0 => self.be(1)? as i8 as i64,
+            0xd1 => self.be(2)? as i16 as i64,
+            0xd2 => self.be(4)? as i32 as i64,
+            0xd3 => self.be(8)? as i64,
+            _ => bail!("expected integer in msgpack, got 0x{b:02x}"),
+        })
+    }
+
+    /// Reads string or binary value.
+    pub fn bytes(&mut self) -> Result<Vec<u8>> {
+        let b = self.byte()?;
+        let len = match b {
+            0xa0..=0xbf => (b & 0x1f) as u64,
+            0xc0 => 0,
+            0xc4 | 0xd9 => self.be(1)?,
+            0xc5 | 0xda => self.be(2)?,
+            0xc6 | 0xdb => self.be(4)?,
+            _ => bail!("expected string in msgpack, got 0x{b:02x}"),
+        };
+        let mut v = vec![0; len as usize];
+        self.reader.read_exact(&mut v)?;
+        Ok(v)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn integers() {
+        let data: &[u8] = &[
+            0x05, 0xff, 0xcc, 0xc8, 0xcd, 0x01, 0x00, 0xce, 0x80, 0x00, 0x00, 0x00, 0xd0, 0x80,
+            0xd1, 0xff, 0x00, 0xd3, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xfe,
+        ];
+        let mut decoder = Decoder::new(data);
+        let values: Vec<i64> = (0..8).map(|_| decoder.int().unwrap()).collect();
+        assert_eq!(values, [5, -1, 200, 256, 1 << 31, -128, -256, -2]);
+        assert!(decoder.at_end().unwrap());
+    }
+
+    #[test]
+    fn strings_and_binaries() {
+        let data: &[u8] = b"\xa3abc\xc0\xc4\x02\xff\x00\xd9\x01x";
+        let mut decoder = Decoder::new(data);
+        assert_eq!(decoder.bytes().unwrap(), b"abc");
+        assert_eq!(decoder.bytes().unwrap(), b"");
+        assert_eq!(decoder.bytes().unwrap(), b"\xff\x00");
+        assert_eq!(decoder.bytes().unwrap(), b"x");
+        assert!(decoder.at_end().unwrap());
+    }
+
+    #[test]
+    fn wrong_type() {
+        assert!(Decoder::new(&b"\xa1a"[..]).int().is_err());
+        assert!(Decoder::new(&b"\x01"[..]).bytes().is_err());
+    }
+}
@@ -0,0 +1,264 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Breakdown of the backed up data by file type.
+use crate::entry::{Entry, Sink};
+use crate::ncdu::BufferedTreeWriter;
+use crate::report::{file_size, human_size};
+use crate::repository::locate;
+use crate::walk::followed_link;
+use anyhow::Result;
+use clap::ValueEnum;
+use clio::Output;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+
+#[derive(ValueEnum, Clone, Copy, Debug)]
+pub enum GroupBy {
+    /// Lowercase file name extension
+    Extension,
+    /// Type recognized from the first bytes of the file, reads every file
+    Content,
+}
+
+/// Known file signatures: offset, magic bytes and the type name.
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"%PDF-", "pdf"),
+    (0, b"\x89PNG\r\n\x1a\n", "png"),
+    (0, b"\xff\xd8\xff", "jpeg"),
+    (0, b"GIF8", "gif"),
+    (0, b"PK\x03\x04", "zip"),
+    (0, b"\x1f\x8b", "gzip"),
+    (0, b"\xfd7zXZ\x00", "xz"),
+    (0, b"BZh", "bzip2"),
+    (0, b"\x28\xb5\x2f\xfd", "zstd"),
+    (0, b"7z\xbc\xaf\x27\x1c", "7z"),
+    (0, b"Rar!\x1a\x07", "rar"),
+    (0, b"\x7fELF", "elf"),
+    (0, b"MZ", "exe"),
+    (0, b"SQLite format 3\x00", "sqlite"),
+    (0, b"QFI\xfb", "qcow2"),
+    (0, b"KDMV", "vmdk"),
+    (0, b"conectix", "vhd"),
+    (0, b"vhdxfile", "vhdx"),
+    (64, b"\x7f\x10\xda\xbe", "vdi"),
+    (0, b"\x1a\x45\xdf\xa3", "matroska"),
+    (4, b"ftyp", "mp4"),
+    (0, b"OggS", "ogg"),
+    (0, b"fLaC", "flac"),
+    (0, b"ID3", "mp3"),
+    (8, b"WAVE", "wav"),
+    (8, b"AVI ", "avi"),
+    (8, b"WEBP", "webp"),
+    (257, b"ustar", "tar"),
+    (0x8001, b"CD001", "iso"),
+];
+
+/// Enough of the file to check all signatures.
+const HEAD_SIZE: usize = 0x8006;
+
+/// Type of the file content, text is anything valid UTF-8 without NUL
+/// bytes in the part that was read. Only regular files are read, opening
+/// e.g. a FIFO could block forever. Links followed by Duplicacy have the type
+/// of their target.
+fn content_type(root: &Path, relative: &Path) -> std::io::Result<&'static str> {
+    let path = root.join(relative);
+    let file_type = match followed_link(root, relative) {
+        Some(_) => std::fs::metadata(&path)?.file_type(),
+        None => std::fs::symlink_metadata(&path)?.file_type(),
+    };
+    if file_type.is_symlink() {
+        return Ok("symlink");
+    } else if !file_type.is_file() {
+        return Ok("special");
+    }
+    let mut head = Vec::with_capacity(HEAD_SIZE);
+    File::open(&path)?
+        .take(HEAD_SIZE as u64)
+        .read_to_end(&mut head)?;
+    let found = SIGNATURES
+        .iter()
+        .find(|(offset, magic, _)| head.get(*offset..offset + magic.len()) == Some(*magic));
+    // The last character may be cut in the middle.
+    let utf8 = std::str::from_utf8(&head).map_or_else(|e| e.error_len().is_none(), |_| true);
+    Ok(match found {
+        Some((_, _, name)) => name,
+        None if head.is_empty() => "empty",
+        None if utf8 && !head.contains(&0) => "text",
+        None => "data",
+    })
+}
+
+fn extension(path: &Path) -> String {
+    match path.extension() {
+        // Names ending with a dot have an empty extension.
+        Some(ext) if !ext.is_empty() => ext.to_string_lossy().to_lowercase(),
+        _ => "(none)".to_owned(),
+    }
+}
+
+#[derive(Default)]
+struct Group {
+    files: u64,
+    bytes: u64,
+}
+
+pub struct TypeReport {
+    output: Option<Output>,
+    /// Path prefixes and repository roots to read files from, empty when the
+    /// filesystem must not be accessed.
+    roots: Vec<(PathBuf, PathBuf)>,
+    group_by: GroupBy,
+    top: usize,
+    groups: HashMap<String, Group>,
+    unreadable: u64,
+    /// Virtual tree with a top-level directory per type, written instead of
+    /// the text report.
+    tree: Option<BufferedTreeWriter>,
+}
+
+impl TypeReport {
+    pub fn new(
+        output: Output,
+        roots: Vec<(PathBuf, PathBuf)>,
+        group_by: GroupBy,
+        top: usize,
+    ) -> Self {
+        TypeReport {
+            output: Some(output),
+            roots,
+            group_by,
+            top,
+            groups: HashMap::new(),
+            unreadable: 0,
+            tree: None,
+        }
+    }
+
+    /// Report writing `tree`, an export where every file is placed under the
+    /// directory named after its type.
+    pub fn tree(
+        tree: BufferedTreeWriter,
+        roots: Vec<(PathBuf, PathBuf)>,
+        group_by: GroupBy,
+    ) -> Self {
+        TypeReport {
+            output: None,
+            roots,
+            group_by,
+            top: 0,
+            groups: HashMap::new(),
+            unreadable: 0,
+            tree: Some(tree),
+        }
+    }
+
+    fn group(&self, path: &Path) -> std::io::Result<String> {
+        match self.group_by {
+            GroupBy::Extension => Ok(extension(path)),
+            GroupBy::Content => match locate(&self.roots, path) {
+                Some((root, relative)) => Ok(content_type(root, relative)?.to_owned()),
+                None => Ok("unknown".to_owned()),
+            },
+        }
+    }
+}
+
+impl Sink for TypeReport {
+    fn entry(&mut self, entry: &Entry) -> Result<()> {
+        if !entry.included || entry.is_dir {
+            return Ok(());
+        }
+        let (Ok(size), Ok(group)) = (file_size(&self.roots, entry), self.group(entry.path)) else {
+            self.unreadable += 1;
+            return Ok(());
+        };
+        if let Some(tree) = &mut self.tree {
+            tree.entry(&Entry {
+                path: &Path::new(&group).join(entry.path),
+                size: Some(size),
+                ..*entry
+            })?;
+        }
+        let usage = self.groups.entry(group).or_default();
+        usage.files += 1;
+        usage.bytes += size;
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        if let Some(tree) = self.tree {
+            if self.unreadable > 0 {
+                eprintln!(
+                    "{} files couldn't be read and are left out",
+                    self.unreadable
+                );
+            }
+            return Box::new(tree).finish();
+        }
+        let mut out = BufWriter::new(self.output.unwrap());
+        let mut groups: Vec<_> = self.groups.iter().collect();
+        groups.sort_by_key(|&(name, g)| (Reverse(g.bytes), name));
+        for (name, g) in groups.iter().take(self.top) {
+            writeln!(
+                out,
+                "{:>12} {:>9} files  {name}",
+                human_size(g.bytes),
+                g.files
+            )?;
+        }
+        if groups.len() > self.top {
+            let rest = &groups[self.top..];
+            let bytes = rest.iter().map(|(_, g)| g.bytes).sum();
+            let files: u64 = rest.iter().map(|(_, g)| g.files).sum();
+            writeln!(
+                out,
+                "{:>12} {files:>9} files  {} other types",
+                human_size(bytes),
+                rest.len()
+            )?;
+        }
+        if self.unreadable > 0 {
+            writeln!(
+                out,
+                "\n{} files couldn't be read and are not counted",
+                self.unreadable
+            )?;
+        }
+        out.into_inner()?.finish()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::symlink;
+    use tempfile::TempDir;
+
+    #[test]
+    fn content_types_of_links() {
+        let outside = TempDir::new().unwrap();
+        let target = outside.path().join("target.txt");
+        std::fs::write(&target, "text").unwrap();
+        let root = TempDir::new().unwrap();
+        let root = root.path();
+        std::fs::create_dir(root.join("d")).unwrap();
+        std::fs::write(root.join("d/empty"), "").unwrap();
+        // Only absolute links in the root pointing outside are followed.
+        symlink(&target, root.join("followed")).unwrap();
+        symlink(&target, root.join("d/link")).unwrap();
+        symlink("d/empty", root.join("relative")).unwrap();
+        symlink(outside.path().join("missing"), root.join("dangling")).unwrap();
+
+        let types = |path: &str| content_type(root, Path::new(path)).ok();
+        assert_eq!(types("d/empty"), Some("empty"));
+        assert_eq!(types("followed"), Some("text"));
+        assert_eq!(types("d/link"), Some("symlink"));
+        assert_eq!(types("relative"), Some("symlink"));
+        assert_eq!(types("dangling"), None);
+        assert_eq!(types("d"), Some("special"));
+    }
+}
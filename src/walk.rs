@@ -0,0 +1,157 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Native replacement for `duplicacy backup -enum-only`.
+use crate::entry::Entry;
+use crate::filters::Filters;
+use anyhow::{Context, Result};
+use std::ffi::OsString;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+/// Target of a symlink in the repository root that Duplicacy follows. It
+/// backs up what absolute links pointing outside of the repository point to,
+/// instead of the links themselves, which lets one repository back up
+/// several directories.
+pub fn followed_link(root: &Path, path: &Path) -> Option<PathBuf> {
+    if path.components().count() != 1 {
+        return None;
+    }
+    let target = std::fs::read_link(root.join(path)).ok()?;
+    (target.is_absolute() && !target.starts_with(root)).then_some(target)
+}
+
+/// Adds names of entries in `dir` to `entries`, with whether they are
+/// directories. Entries read before an error are kept.
+fn list_dir(
+    root: &Path,
+    dir: &Path,
+    entries: &mut Vec<(OsString, std::io::Result<bool>)>,
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(root.join(dir))? {
+        let entry = entry?;
+        // Duplicacy never backs up its own configuration directory.
+        if dir.as_os_str().is_empty() && entry.file_name() == ".duplicacy" {
+            continue;
+        }
+        let is_dir = entry.file_type().map(|file_type| {
+            if dir.as_os_str().is_empty()
+                && file_type.is_symlink()
+                && followed_link(root, Path::new(&entry.file_name())).is_some()
+            {
+                // A dangling link is backed up as nothing, like a vanished file.
+                return std::fs::metadata(entry.path()).is_ok_and(|m| m.is_dir());
+            }
+            file_type.is_dir()
+        });
+        entries.push((entry.file_name(), is_dir));
+    }
+    Ok(())
+}
+
+/// Name the entry is sorted by, Duplicacy appends `/` to directory names, so
+/// e.g. `a-b/` comes before `a/`.
+fn sort_key((name, is_dir): &(OsString, std::io::Result<bool>)) -> impl Iterator<Item = &u8> {
+    let suffix: &[u8] = match is_dir {
+        Ok(true) => b"/",
+        _ => b"",
+    };
+    name.as_bytes().iter().chain(suffix)
+}
+
+/// Walks repository at `root` and calls `f` with every entry included or
+/// excluded by `filters`. Entries are visited in the same order as Duplicacy
+/// visits them: all entries in a directory are reported before entries from
+/// its subdirectories. Directories that can't be listed are reported again
+/// with the read error, and the walk goes on.
+pub fn walk(root: &Path, filters: &Filters, f: impl FnMut(&Entry) -> Result<()>) -> Result<()> {
+    walk_dir(root, PathBuf::new(), filters, f)
+}
+
+/// Walks only the directory `dir`, relative to the repository `root`, the
+/// same way as [`walk`]. The directory itself is not reported.
+pub fn walk_dir(
+    root: &Path,
+    dir: PathBuf,
+    filters: &Filters,
+    mut f: impl FnMut(&Entry) -> Result<()>,
+) -> Result<()> {
+    let mut dirs = vec![dir];
+    while let Some(dir) = dirs.pop() {
+        let mut entries = Vec::new();
+        let listed = list_dir(root, &dir, &mut entries);
+        entries.sort_by(|a, b| sort_key(a).cmp(sort_key(b)));
+        if let Err(err) = listed {
+            // Nothing can be backed up without the repository root, but
+            // other unreadable directories are only reported.
+            if dir.as_os_str().is_empty() {
+                return Err(err).with_context(|| format!("failed to list {}", root.display()));
+            }
+            f(&Entry {
+                path: &dir,
+                is_dir: true,
+                included: true,
+                pattern: None,
+                size: None,
+                stored_size: None,
+                read_error: Some(&err.to_string()),
+            })?;
+        }
+
+        let mut subdirs = Vec::new();
+        for (name, file_type) in entries {
+            let (is_dir, read_error) = match file_type {
+                Ok(is_dir) => (is_dir, None),
+                Err(err) => (false, Some(err.to_string())),
+            };
+            let path = dir.join(name);
+            let mut pattern_path = path.as_os_str().as_bytes().to_vec();
+            if is_dir {
+                pattern_path.push(b'/');
+            }
+            let m = filters.matches(&pattern_path);
+            f(&Entry {
+                path: &path,
+                is_dir,
+                included: m.included,
+                pattern: m.pattern,
+                size: None,
+                stored_size: None,
+                read_error: read_error.as_deref(),
+            })?;
+            if m.included && is_dir {
+                subdirs.push(path);
+            }
+        }
+        // Reversed, so that subdirectories are popped in order.
+        dirs.extend(subdirs.into_iter().rev());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::TempDir;
+
+    #[test]
+    fn duplicacy_order() {
+        let root = TempDir::new().unwrap();
+        for dir in ["a", "a-b", "a/c"] {
+            std::fs::create_dir(root.path().join(dir)).unwrap();
+        }
+        for file in ["a.txt", "a0", "a/b", "a/c/d", "a-b/e"] {
+            std::fs::write(root.path().join(file), "").unwrap();
+        }
+        let mut paths = Vec::new();
+        walk(root.path(), &Filters::default(), |e| {
+            let suffix = if e.is_dir { "/" } else { "" };
+            paths.push(format!("{}{suffix}", e.path.display()));
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            paths,
+            ["a-b/", "a.txt", "a/", "a0", "a-b/e", "a/b", "a/c/", "a/c/d"]
+        );
+    }
+}
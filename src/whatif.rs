@@ -0,0 +1,152 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Evaluation of how changed filters would change the backed up files.
+use crate::entry::{Entry, Sink};
+use crate::filters::Filters;
+use crate::ncdu::BufferedTreeWriter;
+use crate::report::{file_size, human_size};
+use crate::walk::walk_dir;
+use anyhow::Result;
+use std::collections::HashSet;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+
+#[derive(Default)]
+struct Change {
+    files: u64,
+    bytes: u64,
+}
+
+/// Applies the changed `filters` to the entries decided by the current ones
+/// and writes the files that would change to an export, under top-level
+/// `excluded` and `included` directories.
+pub struct WhatIf {
+    filters: Filters,
+    /// Path prefix and repository root, empty when the filesystem must not
+    /// be accessed.
+    roots: Vec<(PathBuf, PathBuf)>,
+    tree: BufferedTreeWriter,
+    /// Currently included directories the changed filters exclude.
+    excluded_dirs: HashSet<PathBuf>,
+    excluded: Change,
+    included: Change,
+    /// Newly included directories that couldn't be walked.
+    unknown_dirs: Vec<PathBuf>,
+}
+
+impl WhatIf {
+    pub fn new(filters: Filters, root: Option<&Path>, tree: BufferedTreeWriter) -> Self {
+        WhatIf {
+            filters,
+            roots: root
+                .map(|r| vec![(PathBuf::new(), r.to_owned())])
+                .unwrap_or_default(),
+            tree,
+            excluded_dirs: HashSet::new(),
+            excluded: Change::default(),
+            included: Change::default(),
+            unknown_dirs: Vec::new(),
+        }
+    }
+
+    fn included(&self, path: &Path, is_dir: bool) -> bool {
+        let mut pattern_path = path.as_os_str().as_bytes().to_vec();
+        if is_dir {
+            pattern_path.push(b'/');
+        }
+        self.filters.matches(&pattern_path).included
+    }
+
+    /// Records a file that changed, `included` is the new decision.
+    fn changed(&mut self, entry: &Entry, included: bool) -> Result<()> {
+        // Files that vanished can't change the backup.
+        let Ok(size) = file_size(&self.roots, entry) else {
+            return Ok(());
+        };
+        let (change, dir) = match included {
+            true => (&mut self.included, "included"),
+            false => (&mut self.excluded, "excluded"),
+        };
+        change.files += 1;
+        change.bytes += size;
+        self.tree.entry(&Entry {
+            path: &Path::new(dir).join(entry.path),
+            included: true,
+            size: Some(size),
+            ..*entry
+        })
+    }
+}
+
+impl Sink for WhatIf {
+    fn entry(&mut self, entry: &Entry) -> Result<()> {
+        let parent_excluded = entry
+            .path
+            .ancestors()
+            .skip(1)
+            .any(|dir| self.excluded_dirs.contains(dir));
+        let included = !parent_excluded && self.included(entry.path, entry.is_dir);
+        if included == entry.included {
+            return Ok(());
+        }
+        match (entry.is_dir, included) {
+            (false, _) => self.changed(entry, included)?,
+            // Files in it still come as included ones.
+            (true, false) => {
+                self.excluded_dirs.insert(entry.path.to_owned());
+            }
+            // Duplicacy didn't descend into it, so nothing in it comes.
+            (true, true) => {
+                let Some((_, root)) = self.roots.first() else {
+                    self.unknown_dirs.push(entry.path.to_owned());
+                    return Ok(());
+                };
+                let root = root.clone();
+                let mut files = Vec::new();
+                walk_dir(&root, entry.path.to_owned(), &self.filters, |e| {
+                    if e.included && !e.is_dir {
+                        files.push(e.path.to_owned());
+                    }
+                    Ok(())
+                })?;
+                for path in files {
+                    let file = Entry {
+                        path: &path,
+                        is_dir: false,
+                        included: false,
+                        pattern: None,
+                        size: None,
+                        stored_size: None,
+                        read_error: None,
+                    };
+                    self.changed(&file, true)?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self: Box<Self>) -> Result<()> {
+        Box::new(self.tree).finish()?;
+        eprintln!(
+            "Newly excluded: {} in {} files",
+            human_size(self.excluded.bytes),
+            self.excluded.files
+        );
+        eprintln!(
+            "Newly included: {} in {} files",
+            human_size(self.included.bytes),
+            self.included.files
+        );
+        if !self.unknown_dirs.is_empty() {
+            eprintln!(
+                "{} newly included directories couldn't be walked without the filesystem:",
+                self.unknown_dirs.len()
+            );
+            for dir in &self.unknown_dirs {
+                eprintln!("  {}", dir.display());
+            }
+        }
+        Ok(())
+    }
+}
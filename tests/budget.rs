@@ -0,0 +1,63 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! Exit status and output of `--budget`.
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+/// Listing in the format of `--line-regex`, so sizes need no filesystem.
+const INPUT: &str = "\
+a/x.iso 3000
+a/y 100
+b/z 100
+";
+
+fn budget(rules: &str) -> Output {
+    let dir = TempDir::new().unwrap();
+    let rules_path = dir.path().join("budget.rules");
+    std::fs::write(&rules_path, rules).unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_duplicacy-du"))
+        .args(["--offline", "--line-regex", r"^(?<path>\S+) (?<size>\d+)$"])
+        .arg("--budget")
+        .arg(&rules_path)
+        .current_dir(dir.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    stdin.write_all(INPUT.as_bytes()).unwrap();
+    drop(stdin);
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn within_limits() {
+    let output = budget("# comment\n\n/ <= 1M, 3 files\na/ <= 2 files\n");
+    assert_eq!(output.status.code(), Some(0), "{output:?}");
+    assert!(output.stdout.is_empty(), "{output:?}");
+}
+
+#[test]
+fn exceeded_limits() {
+    let output = budget("/ <= 2 files\n*.iso <= 2K\nb <= 0 files\n");
+    assert_eq!(output.status.code(), Some(3), "{output:?}");
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert_eq!(
+        stdout,
+        "/: 3 files exceed 2 files (/ <= 2 files)\n\
+         a/x.iso: 2.9 KiB exceeds 2.0 KiB (*.iso <= 2K)\n\
+         b: 1 file exceeds 0 files (b <= 0 files)\n"
+    );
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("3 budget limits exceeded"), "{stderr}");
+}
+
+#[test]
+fn invalid_rules_are_errors() {
+    let output = budget("a/ <= lots\n");
+    assert_eq!(output.status.code(), Some(1), "{output:?}");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(stderr.contains("budget.rules:1"), "{stderr}");
+}
@@ -0,0 +1,31 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2025 Marek Rusinowski
+# SPDX-License-Identifier: Apache-2.0
+"""Writes RSA keys for the tests to OUT, the encrypted ones with passphrase
+`secret`, and a chunk encrypted with the key that is cut after the key.
+
+Usage: mkkeys.py OUT
+
+Needs the `cryptography` package.
+"""
+import os, struct, sys
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import padding, rsa
+
+out = sys.argv[1]
+key = rsa.generate_private_key(public_exponent=65537, key_size=1024)
+formats = {
+    "rsa.pem": (serialization.PrivateFormat.TraditionalOpenSSL, serialization.NoEncryption()),
+    # Written by `openssl genrsa -aes256` of OpenSSL 3.
+    "rsa-pkcs8.pem": (serialization.PrivateFormat.PKCS8, serialization.BestAvailableEncryption(b"secret")),
+    # Written by `openssl genrsa -aes256` of OpenSSL 1.1.
+    "rsa-legacy.pem": (serialization.PrivateFormat.TraditionalOpenSSL, serialization.BestAvailableEncryption(b"secret")),
+}
+for name, (fmt, encryption) in formats.items():
+    with open(os.path.join(out, name), "wb") as f:
+        f.write(key.private_bytes(serialization.Encoding.PEM, fmt, encryption))
+
+oaep = padding.OAEP(mgf=padding.MGF1(hashes.SHA256()), algorithm=hashes.SHA256(), label=None)
+encrypted_key = key.public_key().encrypt(os.urandom(32), oaep)
+with open(os.path.join(out, "truncated-chunk"), "wb") as f:
+    f.write(b"duplicacy\x02" + struct.pack("<H", len(encrypted_key)) + encrypted_key)
@@ -0,0 +1,192 @@
+#!/usr/bin/env python3
+# SPDX-FileCopyrightText: 2025 Marek Rusinowski
+# SPDX-License-Identifier: Apache-2.0
+"""Writes a small Duplicacy storage with one snapshot, used by the tests.
+
+Usage: mkstorage.py OUT LEVEL VERSION [PASSWORD]
+
+LEVEL is the compression level, 100 means LZ4 with BLAKE2b hashes and
+anything else zlib with HMAC-SHA256. VERSION is the snapshot file list
+format, 0 for JSON and 1 for msgpack. Output is deterministic.
+Needs the `cryptography` package for encrypted storages.
+"""
+import base64, hashlib, hmac, json, os, random, struct, sys, zlib
+
+out, level, version = sys.argv[1], int(sys.argv[2]), int(sys.argv[3])
+password = sys.argv[4] if len(sys.argv) > 4 else None
+rng = random.Random(f"{level}-{version}-{password}")
+randbytes = rng.randbytes
+
+if password:
+    hash_key, id_key, chunk_key, file_key, seed = (randbytes(32) for _ in range(5))
+else:
+    hash_key = id_key = seed = b"duplicacy"
+    chunk_key = file_key = b""
+
+
+def keyed_hash(key, data):
+    if level == 100:
+        return hashlib.blake2b(data, digest_size=32, key=key).digest()
+    return hmac.new(key, data, hashlib.sha256).digest()
+
+
+def compress(data):
+    if level != 100:
+        return zlib.compress(data, level)
+    # A single LZ4 block of literals, prefixed with the uncompressed size.
+    n = len(data)
+    block = bytes([min(n, 15) << 4])
+    if n >= 15:
+        m = n - 15
+        while m >= 255:
+            block += b"\xff"
+            m -= 255
+        block += bytes([m])
+    return b"LZ4 " + struct.pack("<I", n) + block + data
+
+
+def encrypt(key, data):
+    data = compress(data)
+    if not key:
+        return data
+    from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+
+    padding = 256 - len(data) % 256
+    nonce = randbytes(12)
+    sealed = AESGCM(key).encrypt(nonce, data + bytes([padding]) * padding, None)
+    return b"duplicacy\x00" + nonce + sealed
+
+
+def derive(key, derivation):
+    return keyed_hash(derivation[-64:], key) if key else b""
+
+
+def write(path, data):
+    os.makedirs(os.path.dirname(path), exist_ok=True)
+    with open(path, "wb") as f:
+        f.write(data)
+
+
+chunk_count = 0
+
+
+def chunk(data):
+    """Stores a chunk, alternating between the storage layouts."""
+    global chunk_count
+    h = keyed_hash(hash_key, data)
+    cid = keyed_hash(id_key, h).hex()
+    path = [
+        f"{cid[:2]}/{cid[2:]}",
+        cid,
+        f"{cid[:2]}/{cid[2:4]}/{cid[4:]}",
+        f"{cid[:2]}/{cid[2:]}.fsl",
+    ][chunk_count % 4]
+    chunk_count += 1
+    write(f"{out}/chunks/{path}", encrypt(derive(chunk_key, h), data))
+    return h.hex()
+
+
+DIR = 1 << 31
+content = randbytes(10000)
+# Path, mode and the file content.
+files = [
+    (b"a/", 0o755 | DIR, b""),
+    (b"a/b/", 0o755 | DIR, b""),
+    (b"a/b/big.bin", 0o644, content[:6000]),
+    (b"a/f.txt", 0o644, content[6000:9000]),
+    (b"c/", 0o755 | DIR, b""),
+    (b"c/caf\xe9", 0o644, content[9000:9500]),
+    (b"c/empty", 0o644, b""),
+    (b"top", 0o644, content[9500:]),
+]
+
+stream = b"".join(f[2] for f in files)
+CHUNK_SIZE = 4096
+chunks = [stream[i : i + CHUNK_SIZE] for i in range(0, len(stream), CHUNK_SIZE)]
+chunk_hashes = [chunk(c) for c in chunks]
+ranges, pos = [], 0
+for _, _, data in files:
+    if data:
+        end = pos + len(data)
+        ranges.append((pos // CHUNK_SIZE, pos % CHUNK_SIZE, (end - 1) // CHUNK_SIZE, (end - 1) % CHUNK_SIZE + 1))
+        pos = end
+    else:
+        ranges.append(None)
+
+
+def mp_int(v):
+    if 0 <= v < 128:
+        return bytes([v])
+    if v < 1 << 16:
+        return b"\xcd" + struct.pack(">H", v)
+    if v < 1 << 32:
+        return b"\xce" + struct.pack(">I", v)
+    return b"\xd3" + struct.pack(">q", v)
+
+
+def mp_str(b):
+    return (bytes([0xA0 | len(b)]) if len(b) < 32 else b"\xd9" + bytes([len(b)])) + b
+
+
+if version == 0:
+    entries = []
+    for (path, mode, data), r in zip(files, ranges):
+        entry = {"size": len(data), "time": 1700000000, "mode": mode}
+        try:
+            entry["path"] = path.decode()
+        except UnicodeDecodeError:
+            entry["name"] = base64.b64encode(path).decode()
+        # Content is written only for regular files.
+        if not mode & DIR:
+            entry["hash"] = hashlib.sha256(data).hexdigest()
+            entry["content"] = "%d:%d:%d:%d" % (r or (0, 0, 0, 0))
+        entries.append(entry)
+    file_list = json.dumps(entries).encode()
+else:
+    file_list, last_end = b"", 0
+    for (path, mode, data), r in zip(files, ranges):
+        if r:
+            delta = (r[0] - last_end, r[1], r[2] - r[0], r[3])
+            last_end = r[2]
+        else:
+            delta = (0, 0, 0, 0)
+        file_list += mp_str(path) + mp_int(len(data)) + mp_int(1700000000) + mp_int(mode)
+        file_list += mp_str(b"") + mp_str(hashlib.sha256(data).digest())
+        file_list += b"".join(mp_int(x) for x in (*delta, 1000, 1000))
+        file_list += mp_int(1) + mp_str(b"user.x") + mp_str(b"y")
+
+snapshot = {
+    "id": "test",
+    "revision": 3,
+    "start_time": 1700000000,
+    "end_time": 1700000001,
+    "files": [chunk(file_list[i : i + 100]) for i in range(0, len(file_list), 100)],
+    "chunks": [chunk(json.dumps(chunk_hashes).encode())],
+    "lengths": [chunk(json.dumps([len(c) for c in chunks]).encode())],
+}
+if version:
+    snapshot["version"] = version
+write(f"{out}/snapshots/test/3", encrypt(derive(file_key, b"snapshots/test/3"), json.dumps(snapshot).encode()))
+
+config = {
+    "compression-level": level,
+    "average-chunk-size": CHUNK_SIZE,
+    "max-chunk-size": CHUNK_SIZE,
+    "min-chunk-size": CHUNK_SIZE,
+    "chunk-seed": seed.hex(),
+    "fixed-nesting": True,
+    "hash-key": hash_key.hex(),
+    "id-key": id_key.hex(),
+    "chunk-key": chunk_key.hex(),
+    "file-key": file_key.hex(),
+}
+config = json.dumps(config, indent=4).encode()
+if password:
+    from cryptography.hazmat.primitives.ciphers.aead import AESGCM
+
+    salt, iterations = randbytes(32), 16384
+    key = hashlib.pbkdf2_hmac("sha256", password.encode(), salt, iterations, 32)
+    sealed = encrypt(key, config)
+    write(f"{out}/config", b"duplicacy\x01" + salt + struct.pack("<I", iterations) + sealed[len(b"duplicacy\x00"):])
+else:
+    write(f"{out}/config", config)
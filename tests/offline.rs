@@ -0,0 +1,56 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! `--offline` on logs with and without file sizes.
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+use tempfile::TempDir;
+
+const LOG: &str = "\
+2025-01-01 00:00:00.000 DEBUG PATTERN_INCLUDE a/x is included
+2025-01-01 00:00:00.000 DEBUG PATTERN_INCLUDE a/y is included
+2025-01-01 00:00:00.000 DEBUG PATTERN_EXCLUDE b is excluded by pattern -b
+";
+
+fn run(args: &[&str], input: &str) -> Output {
+    // Nothing from the directory it's run in may be used.
+    let cwd = TempDir::new().unwrap();
+    let mut child = Command::new(env!("CARGO_BIN_EXE_duplicacy-du"))
+        .args(["--offline", "--report"])
+        .args(args)
+        .current_dir(cwd.path())
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success(), "{output:?}");
+    output
+}
+
+#[test]
+fn warns_about_missing_sizes() {
+    let output = run(&[], LOG);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert_eq!(
+        stderr.matches("the input has no file sizes").count(),
+        1,
+        "{stderr}"
+    );
+}
+
+#[test]
+fn sizes_from_line_regex() {
+    let log = "a/x 100\na/y 20\n";
+    let output = run(&["--line-regex", r"^(?<path>\S+) (?<size>\d+)$"], log);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(!stderr.contains("no file sizes"), "{stderr}");
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    assert!(stdout.contains("120 B in 2 files"), "{stdout}");
+}
@@ -0,0 +1,114 @@
+// SPDX-FileCopyrightText: 2025 Marek Rusinowski
+// SPDX-License-Identifier: Apache-2.0
+//! `duplicacy-du scan` against a fake `duplicacy` found in PATH.
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::process::{Command, Output};
+use tempfile::TempDir;
+
+/// Records its working directory and arguments, one per line, to the
+/// `$FAKE_ARGS` file and writes a log like `backup -enum-only` does.
+const FAKE_DUPLICACY: &str = r#"#!/bin/sh
+pwd -P > "$FAKE_ARGS"
+printf '%s\n' "$@" >> "$FAKE_ARGS"
+echo '2025-01-01 00:00:00.000 DEBUG PATTERN_INCLUDE a is included'
+echo '2025-01-01 00:00:00.000 DEBUG PATTERN_EXCLUDE b is excluded by pattern -b'
+if [ -n "$FAKE_FAIL" ]; then
+    echo 'Storage X has not been initialized' >&2
+    exit 100
+fi
+"#;
+
+struct Fixture {
+    bin: TempDir,
+    repository: TempDir,
+}
+
+impl Fixture {
+    fn new() -> Self {
+        let bin = TempDir::new().unwrap();
+        let duplicacy = bin.path().join("duplicacy");
+        std::fs::write(&duplicacy, FAKE_DUPLICACY).unwrap();
+        std::fs::set_permissions(&duplicacy, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let repository = TempDir::new().unwrap();
+        std::fs::create_dir(repository.path().join(".duplicacy")).unwrap();
+        std::fs::write(repository.path().join("a"), "abc").unwrap();
+        Fixture { bin, repository }
+    }
+
+    fn args_path(&self) -> std::path::PathBuf {
+        self.bin.path().join("args")
+    }
+
+    /// Runs scan from an unrelated directory, so the repository is known
+    /// only from --repository.
+    fn scan(&self, fail: bool) -> Output {
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let mut paths = vec![self.bin.path().to_owned()];
+        paths.extend(std::env::split_paths(&path));
+        let mut command = Command::new(env!("CARGO_BIN_EXE_duplicacy-du"));
+        command
+            .args(["scan", "--repository"])
+            .arg(self.repository.path())
+            .args(["--storage", "X", "--", "-threads", "4"])
+            .current_dir(self.bin.path())
+            .env("PATH", std::env::join_paths(paths).unwrap())
+            .env("FAKE_ARGS", self.args_path());
+        if fail {
+            command.env("FAKE_FAIL", "1");
+        }
+        command.output().unwrap()
+    }
+}
+
+fn canonical(path: &Path) -> String {
+    path.canonicalize().unwrap().to_str().unwrap().to_owned()
+}
+
+#[test]
+fn runs_enum_only_backup_in_repository() {
+    let fixture = Fixture::new();
+    let output = fixture.scan(false);
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(output.status.success(), "{stderr}");
+
+    let recorded = std::fs::read_to_string(fixture.args_path()).unwrap();
+    let mut lines = recorded.lines();
+    assert_eq!(
+        lines.next(),
+        Some(canonical(fixture.repository.path()).as_str())
+    );
+    let args: Vec<_> = lines.collect();
+    assert_eq!(
+        args,
+        [
+            "-log",
+            "-debug",
+            "backup",
+            "-enum-only",
+            "-storage",
+            "X",
+            "-threads",
+            "4"
+        ]
+    );
+
+    // Only the included file is in the export, with its size from the
+    // repository.
+    let export = String::from_utf8(output.stdout).unwrap();
+    assert!(export.contains(r#""name":"a","asize":3"#), "{export}");
+    assert!(!export.contains(r#""name":"b""#), "{export}");
+}
+
+#[test]
+fn reports_duplicacy_failure() {
+    let fixture = Fixture::new();
+    let output = fixture.scan(true);
+    assert_eq!(output.status.code(), Some(1));
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("failed with exit status: 100"), "{stderr}");
+    assert!(
+        stderr.contains("Storage X has not been initialized"),
+        "{stderr}"
+    );
+}